@@ -1,14 +1,37 @@
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
 use clap::Parser;
 use lazy_static::lazy_static;
+use ipnet::IpNet;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
 use crate::fs;
 
 lazy_static! {
-    pub static ref ARGS: Args = Args::parse();
+    pub static ref ARGS: Args = parse();
+    // digests of the last accepted value of every hashed secret, see SecretArg::verify
+    static ref VERIFIED_SECRETS: Mutex<HashMap<String, Vec<u8>>> = Mutex::new(HashMap::new());
+}
+
+#[cfg(not(test))]
+fn parse() -> Args {
+    Args::parse()
+}
+
+// tests are started with the arguments of the test harness, so they get the defaults
+// instead, with a data directory of their own
+#[cfg(test)]
+fn parse() -> Args {
+    let data_dir = std::env::temp_dir().join(format!("microbin-test-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).expect("the temporary directory is writable");
+    Args::parse_from(["microbin", "--data-dir", &data_dir.to_string_lossy()])
 }
 
 #[derive(Parser, Debug, Clone, Serialize)]
@@ -50,8 +73,29 @@ pub struct Args {
     #[clap(short, long, env = "MICROBIN_PORT", default_value_t = 8080)]
     pub port: u16,
 
-    #[clap(short, long, env="MICROBIN_BIND", default_value_t = IpAddr::from([0, 0, 0, 0]))]
-    pub bind: IpAddr,
+    #[clap(short, long, env = "MICROBIN_BIND", value_delimiter = ',', default_value = "0.0.0.0")]
+    pub bind: Vec<IpAddr>,
+
+    #[clap(long, env = "MICROBIN_UNIX_SOCKET")]
+    pub unix_socket: Option<String>,
+
+    #[clap(long, env = "MICROBIN_UNIX_SOCKET_MODE", default_value = "660")]
+    pub unix_socket_mode: String,
+
+    #[clap(long, env = "MICROBIN_UNIX_SOCKET_ONLY")]
+    pub unix_socket_only: bool,
+
+    #[clap(long, env = "MICROBIN_TRUST_UNIX_SOCKET")]
+    pub trust_unix_socket: bool,
+
+    #[clap(long, env = "MICROBIN_TLS_CERT")]
+    pub tls_cert: Option<String>,
+
+    #[clap(long, env = "MICROBIN_TLS_KEY")]
+    pub tls_key: Option<String>,
+
+    #[clap(long, env = "MICROBIN_TLS_REDIRECT_PORT")]
+    pub tls_redirect_port: Option<u16>,
 
     #[clap(long, env = "MICROBIN_PRIVATE", action = clap::ArgAction::Set, default_value_t = true)]
     pub private: bool,
@@ -110,6 +154,14 @@ pub struct Args {
     #[clap(long, env = "MICROBIN_MAX_EXPIRY", default_value = "1week")]
     pub max_expiry: String,
 
+    #[clap(
+        long,
+        env = "MICROBIN_EXPIRY_PRESETS",
+        value_delimiter = ',',
+        default_value = "1min,10min,1hour,24hour,3days,1week,1month,6months,1year,2years,4years,8years,16years"
+    )]
+    pub expiry_presets: Vec<String>,
+
     #[clap(long, env = "MICROBIN_DATA_DIR", default_value = "microbin_data")]
     pub data_dir: String,
 
@@ -131,6 +183,51 @@ pub struct Args {
     #[clap(long, env = "MICROBIN_DISABLE_UPDATE_CHECKING")]
     pub disable_update_checking: bool,
 
+    #[clap(long, env = "MICROBIN_DISABLE_SECURITY_HEADERS")]
+    pub disable_security_headers: bool,
+
+    #[clap(long, env = "MICROBIN_FRAME_ANCESTORS")]
+    pub frame_ancestors: Option<String>,
+
+    #[clap(long, env = "MICROBIN_FILES_ORIGIN")]
+    pub files_origin: Option<PublicUrl>,
+
+    #[clap(long, env = "MICROBIN_ENABLE_USERS")]
+    pub enable_users: bool,
+
+    #[clap(long, env = "MICROBIN_OIDC_ISSUER")]
+    pub oidc_issuer: Option<PublicUrl>,
+
+    #[clap(long, env = "MICROBIN_OIDC_CLIENT_ID")]
+    pub oidc_client_id: Option<String>,
+
+    #[clap(long, env = "MICROBIN_OIDC_CLIENT_SECRET")]
+    pub oidc_client_secret: Option<SecretArg>,
+
+    #[clap(long, env = "MICROBIN_OIDC_SCOPES", default_value = "openid profile email")]
+    pub oidc_scopes: String,
+
+    #[clap(long, env = "MICROBIN_OIDC_ADMIN_CLAIM", default_value = "groups")]
+    pub oidc_admin_claim: String,
+
+    #[clap(long, env = "MICROBIN_OIDC_ADMIN_VALUE")]
+    pub oidc_admin_value: Option<String>,
+
+    #[clap(long, env = "MICROBIN_AUTH_PROXY_HEADER")]
+    pub auth_proxy_header: Option<String>,
+
+    #[clap(long, env = "MICROBIN_AUTH_PROXY_TRUSTED", value_delimiter = ',')]
+    pub auth_proxy_trusted: Vec<IpNet>,
+
+    #[clap(long, env = "MICROBIN_AUTH_PROXY_GROUPS_HEADER")]
+    pub auth_proxy_groups_header: Option<String>,
+
+    #[clap(long, env = "MICROBIN_AUTH_PROXY_ADMIN_GROUP")]
+    pub auth_proxy_admin_group: Option<String>,
+
+    #[clap(long, env = "MICROBIN_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
     #[clap(long, env = "MICROBIN_ENCRYPTION_CLIENT_SIDE", action = clap::ArgAction::Set, default_value_t = true)]
     pub encryption_client_side: bool,
 
@@ -154,6 +251,14 @@ pub struct Args {
     )]
     pub max_file_size_unencrypted_mb: usize,
 
+    #[clap(long, env = "MICROBIN_MAX_LOGIN_ATTEMPTS", default_value_t = 5)]
+    pub max_login_attempts: u32,
+
+    #[clap(long, env = "MICROBIN_LOCKOUT_SECONDS", default_value_t = 60)]
+    pub lockout_seconds: u64,
+
+    #[clap(long, env = "MICROBIN_SESSION_IDLE_MINUTES", default_value_t = 30)]
+    pub session_idle_minutes: u64,
 
 
     #[clap(long, env = "MICROBIN_DEFAULT_VIEW", default_value = "gallery")]
@@ -179,24 +284,12 @@ impl Args {
         }
     }
 
-    pub fn max_expiry_index(&self) -> usize {
-        let options = &[
-            "1min",
-            "10min",
-            "1hour",
-            "24hour",
-            "3days",
-            "1week",
-            "1month",
-            "6months",
-            "1year",
-            "2years",
-            "4years",
-            "8years",
-            "16years",
-            "never",
-        ];
-        options.iter().position(|&x| x == self.max_expiry).unwrap_or(5)
+    pub fn bind_as_string(&self) -> String {
+        self.bind
+            .iter()
+            .map(|bind| bind.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
     }
 
     pub fn without_secrets(self) -> Args {
@@ -214,6 +307,13 @@ impl Args {
             highlightsyntax: self.highlightsyntax,
             port: self.port,
             bind: self.bind,
+            unix_socket: self.unix_socket.map(|_| String::from("")),
+            unix_socket_mode: self.unix_socket_mode,
+            unix_socket_only: self.unix_socket_only,
+            trust_unix_socket: self.trust_unix_socket,
+            tls_cert: self.tls_cert.map(|_| String::from("")),
+            tls_key: None,
+            tls_redirect_port: self.tls_redirect_port,
             private: self.private,
             pure_html: self.pure_html,
             json_db: self.json_db,
@@ -234,6 +334,7 @@ impl Args {
             enable_readonly: self.enable_readonly,
             default_expiry: self.default_expiry,
             max_expiry: self.max_expiry,
+            expiry_presets: self.expiry_presets,
             data_dir: String::from(""),
             no_file_upload: self.no_file_upload,
             custom_css: self.custom_css,
@@ -244,7 +345,25 @@ impl Args {
             default_privacy: None,
             max_file_size_encrypted_mb: self.max_file_size_encrypted_mb,
             max_file_size_unencrypted_mb: self.max_file_size_unencrypted_mb,
+            max_login_attempts: self.max_login_attempts,
+            lockout_seconds: self.lockout_seconds,
+            session_idle_minutes: self.session_idle_minutes,
             disable_update_checking: self.disable_update_checking,
+            disable_security_headers: self.disable_security_headers,
+            frame_ancestors: self.frame_ancestors,
+            files_origin: self.files_origin,
+            enable_users: self.enable_users,
+            oidc_issuer: self.oidc_issuer,
+            oidc_client_id: self.oidc_client_id,
+            oidc_client_secret: None,
+            oidc_scopes: self.oidc_scopes,
+            oidc_admin_claim: self.oidc_admin_claim,
+            oidc_admin_value: self.oidc_admin_value,
+            auth_proxy_header: self.auth_proxy_header,
+            auth_proxy_trusted: self.auth_proxy_trusted,
+            auth_proxy_groups_header: self.auth_proxy_groups_header,
+            auth_proxy_admin_group: self.auth_proxy_admin_group,
+            trusted_proxies: self.trusted_proxies,
 
             default_view: self.default_view,
         }
@@ -263,19 +382,69 @@ impl fmt::Display for PublicUrl {
 #[derive(Debug, Clone, Serialize)]
 pub struct SecretArg(pub String);
 
+impl SecretArg {
+    /// Whether the secret is given as an Argon2 hash in PHC format or a bcrypt hash.
+    /// Anything that does not parse as one is a plain secret, even if it looks alike.
+    pub fn is_hash(&self) -> bool {
+        if self.0.starts_with("$argon2") {
+            PasswordHash::new(&self.0).is_ok_and(|hash| hash.salt.is_some() && hash.hash.is_some())
+        } else {
+            self.0.len() == 60 && bcrypt::HashParts::from_str(&self.0).is_ok()
+        }
+    }
+
+    /// Checks a submitted value against the secret, in constant time for plain secrets.
+    pub fn verify(&self, candidate: &str) -> bool {
+        if !self.is_hash() {
+            return candidate.as_bytes().ct_eq(self.0.as_bytes()).into();
+        }
+
+        // hashes are slow to check on purpose, and basic auth checks the password on
+        // every request, so a value that was accepted once is remembered
+        let digest = Sha256::digest(candidate.as_bytes()).to_vec();
+        if let Some(accepted) = VERIFIED_SECRETS.lock().unwrap().get(&self.0) {
+            if bool::from(accepted.ct_eq(&digest)) {
+                return true;
+            }
+        }
+
+        let valid = if self.0.starts_with("$argon2") {
+            PasswordHash::new(&self.0)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(candidate.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        } else {
+            bcrypt::verify(candidate, &self.0).unwrap_or(false)
+        };
+
+        if valid {
+            VERIFIED_SECRETS
+                .lock()
+                .unwrap()
+                .insert(self.0.clone(), digest);
+        }
+        valid
+    }
+}
+
 impl FromStr for SecretArg {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(path) = s.strip_prefix("file://").or_else(|| s.strip_prefix("file:")) {
+        let secret = if let Some(path) = s.strip_prefix("file://").or_else(|| s.strip_prefix("file:")) {
             // Read value from file
             fs::read_to_string(path)
                 .map(|content| SecretArg(content.trim().to_string()))
-                .map_err(|e| format!("Failed to read secret from {}: {}", path, e))
+                .map_err(|e| format!("Failed to read secret from {}: {}", path, e))?
         } else {
             // Use the raw string provided
-            Ok(SecretArg(s.to_string()))
-        }
+            SecretArg(s.to_string())
+        };
+
+        Ok(secret)
     }
 }
 
@@ -295,3 +464,32 @@ impl FromStr for PublicUrl {
         Ok(PublicUrl(uri))
     }
 }
+
+#[test]
+fn test_secret_arg_verify() {
+    use argon2::password_hash::rand_core::OsRng;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    let plain = SecretArg::from_str("m1cr0b1n").unwrap();
+    assert!(plain.verify("m1cr0b1n"));
+    assert!(!plain.verify("m1cr0b1"));
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(b"m1cr0b1n", &salt).unwrap();
+    let argon2 = SecretArg::from_str(&hash.to_string()).unwrap();
+    assert!(argon2.is_hash());
+    assert!(argon2.verify("m1cr0b1n"));
+    assert!(!argon2.verify("wrong"));
+
+    let bcrypt = SecretArg(bcrypt::hash("m1cr0b1n", 4).unwrap());
+    assert!(bcrypt.verify("m1cr0b1n"));
+    assert!(bcrypt.verify("m1cr0b1n"));
+    assert!(!bcrypt.verify("wrong"));
+
+    // plain passwords that merely look like hashes
+    for value in ["$argon2id$broken", "$2y$plain password"] {
+        let plain = SecretArg::from_str(value).unwrap();
+        assert!(!plain.is_hash());
+        assert!(plain.verify(value));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::args::{Args, ARGS};
+use crate::pasta::Pasta;
+use crate::util::attempts;
+use crate::util::auth::read_fields;
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::misc::remove_expired;
+use crate::util::session::{removal_cookie, Principal};
+use crate::AppState;
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use std::cmp::Reverse;
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate<'a> {
+    args: &'a Args,
+    status: String,
+    csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "my_uploads.html")]
+struct MyUploadsTemplate<'a> {
+    args: &'a Args,
+    username: &'a str,
+    sso: bool,
+    pastas: &'a Vec<&'a Pasta>,
+    status: String,
+    csrf_token: String,
+}
+
+fn redirect(path: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}{}", ARGS.public_path_as_str(), path)))
+        .finish()
+}
+
+fn login_page(req: &HttpRequest, status: String) -> HttpResponse {
+    if !ARGS.enable_users {
+        return redirect("/");
+    }
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        LoginTemplate {
+            args: &ARGS,
+            status,
+            csrf_token: csrf::token(req),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[get("/login")]
+pub async fn get_login(req: HttpRequest) -> HttpResponse {
+    login_page(&req, String::from(""))
+}
+
+#[get("/login/{status}")]
+pub async fn get_login_with_status(param: web::Path<String>, req: HttpRequest) -> HttpResponse {
+    login_page(&req, param.into_inner())
+}
+
+#[post("/login")]
+pub async fn post_login(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !ARGS.enable_users {
+        return Ok(redirect("/"));
+    }
+
+    let [username, password, csrf_token] =
+        read_fields(payload, ["username", "password", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let subjects = [
+        attempts::client_subject(&req),
+        attempts::user_subject(&username),
+    ];
+    if data.attempts.is_locked(&subjects) {
+        return Ok(redirect("/login/locked"));
+    }
+
+    match data.users.verify(&username, &password) {
+        Some(id) => {
+            data.attempts.record_success(&subjects[1]);
+            Ok(HttpResponse::Found()
+                .append_header(("Location", format!("{}/my_uploads", ARGS.public_path_as_str())))
+                .cookie(data.sessions.create(Principal::User(id)))
+                .finish())
+        }
+        None => {
+            data.attempts.record_failure(&subjects);
+            Ok(redirect("/login/incorrect"))
+        }
+    }
+}
+
+#[post("/logout")]
+pub async fn post_logout(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let [csrf_token] = read_fields(payload, [CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    data.sessions.destroy(&req);
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/login/logged_out", ARGS.public_path_as_str())))
+        .cookie(removal_cookie())
+        .finish())
+}
+
+fn my_uploads_page(data: web::Data<AppState>, req: &HttpRequest, status: String) -> HttpResponse {
+    let user = match data.sessions.user(req).and_then(|id| data.users.find(id)) {
+        Some(user) => user,
+        None => return redirect("/login"),
+    };
+
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let mut owned: Vec<&Pasta> = pastas
+        .iter()
+        .filter(|pasta| pasta.owner == Some(user.id))
+        .collect();
+    // newest first
+    owned.sort_by_key(|pasta| Reverse(pasta.created));
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        MyUploadsTemplate {
+            args: &ARGS,
+            username: &user.username,
+            sso: user.is_sso(),
+            pastas: &owned,
+            status,
+            csrf_token: csrf::token(req),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[get("/my_uploads")]
+pub async fn get_my_uploads(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    my_uploads_page(data, &req, String::from(""))
+}
+
+#[get("/my_uploads/{status}")]
+pub async fn get_my_uploads_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    my_uploads_page(data, &req, param.into_inner())
+}
+
+#[post("/my_uploads/password")]
+pub async fn post_change_password(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let [current_password, new_password, csrf_token] = read_fields(
+        payload,
+        ["current_password", "new_password", CSRF_FIELD],
+    )
+    .await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let user = match data.sessions.user(&req).and_then(|id| data.users.find(id)) {
+        Some(user) => user,
+        None => return Ok(redirect("/login/expired")),
+    };
+
+    let subjects = [
+        attempts::client_subject(&req),
+        attempts::user_subject(&user.username),
+    ];
+    if data.attempts.is_locked(&subjects) {
+        return Ok(redirect("/my_uploads/locked"));
+    }
+    if data.users.verify(&user.username, &current_password).is_none() {
+        data.attempts.record_failure(&subjects);
+        return Ok(redirect("/my_uploads/incorrect"));
+    }
+    data.attempts.record_success(&subjects[1]);
+
+    if let Err(status) = data.users.set_password(user.id, &new_password) {
+        return Ok(redirect(&format!("/my_uploads/{}", status)));
+    }
+
+    // sessions elsewhere may have been started by someone who knew the old password
+    data.sessions.destroy_user(user.id);
+    log::info!("User {} changed their password.", user.username);
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/my_uploads/password_changed", ARGS.public_path_as_str())))
+        .cookie(data.sessions.create(Principal::User(user.id)))
+        .finish())
+}
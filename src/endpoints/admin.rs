@@ -1,12 +1,20 @@
 use crate::args::{Args, ARGS};
 use crate::pasta::Pasta;
+use crate::util::attempts::{self, Lockout, ADMIN_SUBJECT};
+use crate::util::auth::read_fields;
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::db::update;
 use crate::util::misc::remove_expired;
+use crate::util::misc::string_to_qr_svg;
+use crate::util::security_headers;
+use crate::util::session::{removal_cookie, Principal};
+use crate::util::two_factor::{new_secret, provisioning_uri};
+use crate::util::users::User;
 use crate::util::version::{fetch_latest_version, Version, CURRENT_VERSION};
 use crate::AppState;
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use askama::Template;
-use futures::TryStreamExt;
 
 #[derive(Template)]
 #[template(path = "admin.html")]
@@ -17,38 +25,116 @@ struct AdminTemplate<'a> {
     version_string: &'a String,
     message: &'a String,
     update: &'a Option<Version>,
+    lockouts: &'a Vec<Lockout>,
+    two_factor_enabled: bool,
+    recovery_codes_left: usize,
+    user_count: usize,
+    csrf_token: String,
+    nonce: String,
 }
 
-#[get("/admin")]
-pub async fn get_admin() -> Result<HttpResponse, Error> {
-    return Ok(HttpResponse::Found()
-        .append_header(("Location", format!("{}/auth_admin", ARGS.public_path_as_str())))
-        .finish());
+#[derive(Template)]
+#[template(path = "admin_2fa.html")]
+struct TwoFactorTemplate<'a> {
+    args: &'a Args,
+    status: &'a str,
+    secret: &'a str,
+    qr: &'a str,
+    recovery_codes: &'a [String],
+    csrf_token: String,
+}
+
+struct UserRow {
+    user: User,
+    uploads: usize,
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+struct UsersTemplate<'a> {
+    args: &'a Args,
+    status: &'a str,
+    users: &'a Vec<UserRow>,
+    csrf_token: String,
+}
+
+/// Checks the admin credentials and, if enrolled, the two-factor code, counting failures
+/// against the client and the admin login. Returns the status to redirect to if they were
+/// not accepted.
+fn check_admin_login(
+    data: &AppState,
+    req: &HttpRequest,
+    username: &str,
+    password: &str,
+    code: &str,
+) -> Option<&'static str> {
+    let subjects = [attempts::client_subject(req), ADMIN_SUBJECT.to_string()];
+    if data.attempts.is_locked(&subjects) {
+        return Some("locked");
+    }
+
+    if !ARGS.auth_admin_username.verify(username) || !ARGS.auth_admin_password.verify(password) {
+        data.attempts.record_failure(&subjects);
+        return Some("incorrect");
+    }
+
+    if data.two_factor.is_enabled() && !data.two_factor.verify(code) {
+        data.attempts.record_failure(&subjects);
+        return Some("incorrect_code");
+    }
+
+    data.attempts.record_success(ADMIN_SUBJECT);
+    None
 }
 
-#[post("/admin")]
-pub async fn post_admin(
+fn redirect_to_login(status: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/auth_admin/{}", ARGS.public_path_as_str(), status)))
+        .finish()
+}
+
+#[post("/admin/login")]
+pub async fn post_admin_login(
     data: web::Data<AppState>,
-    mut payload: Multipart,
+    payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    let mut username = String::from("");
-    let mut password = String::from("");
+    let [username, password, code, csrf_token] =
+        read_fields(payload, ["username", "password", "code", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
 
-    while let Some(mut field) = payload.try_next().await? {
-        if field.name() == Some("username") {
-            while let Some(chunk) = field.try_next().await? {
-                username.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
-            }
-        } else if field.name() == Some("password") {
-            while let Some(chunk) = field.try_next().await? {
-                password.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
-            }
-        }
+    if let Some(status) = check_admin_login(&data, &req, &username, &password, &code) {
+        return Ok(redirect_to_login(status));
     }
 
-    if username != **ARGS.auth_admin_username || password != *ARGS.auth_admin_password {
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin", ARGS.public_path_as_str())))
+        .cookie(data.sessions.create(Principal::Admin))
+        .finish())
+}
+
+#[post("/admin/logout")]
+pub async fn post_admin_logout(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let [csrf_token] = read_fields(payload, [CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    data.sessions.destroy(&req);
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/auth_admin/logged_out", ARGS.public_path_as_str())))
+        .cookie(removal_cookie())
+        .finish())
+}
+
+#[get("/admin")]
+pub async fn get_admin(data: web::Data<AppState>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
         return Ok(HttpResponse::Found()
-            .append_header(("Location", format!("{}/auth_admin/incorrect", ARGS.public_path_as_str())))
+            .append_header(("Location", format!("{}/auth_admin", ARGS.public_path_as_str())))
             .finish());
     }
 
@@ -68,7 +154,8 @@ pub async fn post_admin(
         message = "Warning: No public URL set with --public-path parameter. QR code and URL Copying functions have been disabled"
     }
 
-    if *ARGS.auth_admin_username == "admin" && *ARGS.auth_admin_password == "m1cr0b1n" {
+    // the password may be configured as a hash, which only verify can compare
+    if ARGS.auth_admin_username.verify("admin") && ARGS.auth_admin_password.verify("m1cr0b1n") {
         status = "WARNING";
         message = "Warning: You are using the default admin login details. This is a security risk, please change them."
     }
@@ -99,8 +186,280 @@ pub async fn post_admin(
             version_string: &format!("{}", CURRENT_VERSION.long_title),
             message: &String::from(message),
             update: &update,
+            lockouts: &data.attempts.lockouts(),
+            two_factor_enabled: data.two_factor.is_enabled(),
+            recovery_codes_left: data.two_factor.recovery_codes_left(),
+            user_count: data.users.list().len(),
+            csrf_token: csrf::token(&req),
+            nonce: security_headers::nonce(&req),
         }
         .render()
         .unwrap(),
     ))
 }
+
+#[post("/admin/lockouts")]
+pub async fn post_clear_lockouts(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [subject, csrf_token] = read_fields(payload, ["subject", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    if subject.is_empty() || subject == "all" {
+        data.attempts.clear(None);
+        log::info!("Cleared all lockouts.");
+    } else {
+        data.attempts.clear(Some(&subject));
+        log::info!("Cleared lockout of {}.", subject);
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin", ARGS.public_path_as_str())))
+        .finish())
+}
+
+fn two_factor_page(
+    req: &HttpRequest,
+    status: &str,
+    secret: &str,
+    recovery_codes: &[String],
+) -> HttpResponse {
+    let qr = if secret.is_empty() {
+        String::new()
+    } else {
+        string_to_qr_svg(&provisioning_uri(secret))
+    };
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        TwoFactorTemplate {
+            args: &ARGS,
+            status,
+            secret,
+            qr: &qr,
+            recovery_codes,
+            csrf_token: csrf::token(req),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[get("/admin/2fa")]
+pub async fn get_two_factor(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    if !data.sessions.is_admin(&req) {
+        return redirect_to_login("expired");
+    }
+    if data.two_factor.is_enabled() {
+        return two_factor_page(&req, "enabled", "", &[]);
+    }
+
+    two_factor_page(&req, "", &new_secret(), &[])
+}
+
+#[post("/admin/2fa")]
+pub async fn post_enable_two_factor(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [secret, code, csrf_token] = read_fields(payload, ["secret", "code", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    match data.two_factor.enable(&secret, &code) {
+        Some(recovery_codes) => {
+            log::info!("Two-factor authentication enabled for the admin login.");
+            Ok(two_factor_page(&req, "success", "", &recovery_codes))
+        }
+        None => Ok(two_factor_page(&req, "incorrect", &secret, &[])),
+    }
+}
+
+#[post("/admin/2fa/recovery_codes")]
+pub async fn post_regenerate_recovery_codes(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [csrf_token] = read_fields(payload, [CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    match data.two_factor.regenerate_recovery_codes() {
+        Some(recovery_codes) => Ok(two_factor_page(&req, "regenerated", "", &recovery_codes)),
+        None => Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/admin/2fa", ARGS.public_path_as_str())))
+            .finish()),
+    }
+}
+
+#[post("/admin/2fa/disable")]
+pub async fn post_disable_two_factor(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [code, csrf_token] = read_fields(payload, ["code", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    if !data.two_factor.verify(&code) {
+        return Ok(two_factor_page(&req, "incorrect_disable", "", &[]));
+    }
+
+    data.two_factor.disable();
+    log::warn!("Two-factor authentication disabled for the admin login.");
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin", ARGS.public_path_as_str())))
+        .finish())
+}
+
+fn users_page(data: &AppState, req: &HttpRequest, status: &str) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+    remove_expired(&mut pastas);
+
+    let users: Vec<UserRow> = data
+        .users
+        .list()
+        .into_iter()
+        .map(|user| UserRow {
+            uploads: pastas.iter().filter(|pasta| pasta.owner == Some(user.id)).count(),
+            user,
+        })
+        .collect();
+
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        UsersTemplate {
+            args: &ARGS,
+            status,
+            users: &users,
+            csrf_token: csrf::token(req),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+fn redirect_to_users(status: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/admin/users/{}", ARGS.public_path_as_str(), status)))
+        .finish()
+}
+
+#[get("/admin/users")]
+pub async fn get_users(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    if !data.sessions.is_admin(&req) {
+        return redirect_to_login("expired");
+    }
+
+    users_page(&data, &req, "")
+}
+
+#[get("/admin/users/{status}")]
+pub async fn get_users_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !data.sessions.is_admin(&req) {
+        return redirect_to_login("expired");
+    }
+
+    users_page(&data, &req, &param.into_inner())
+}
+
+#[post("/admin/users")]
+pub async fn post_create_user(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [username, password, csrf_token] =
+        read_fields(payload, ["username", "password", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    match data.users.create(&username, &password) {
+        Ok(user) => {
+            log::info!("Created user {}.", user.username);
+            Ok(redirect_to_users("created"))
+        }
+        Err(status) => Ok(redirect_to_users(status)),
+    }
+}
+
+#[post("/admin/users/password")]
+pub async fn post_reset_user_password(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [user_id, password, csrf_token] =
+        read_fields(payload, ["user_id", "password", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let id = user_id.parse::<u64>().unwrap_or(0);
+    if let Err(status) = data.users.set_password(id, &password) {
+        return Ok(redirect_to_users(status));
+    }
+    data.sessions.destroy_user(id);
+    log::info!("Reset the password of user {}.", data.users.username(id).unwrap_or_default());
+
+    Ok(redirect_to_users("password_reset"))
+}
+
+#[post("/admin/users/remove")]
+pub async fn post_remove_user(
+    data: web::Data<AppState>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login("expired"));
+    }
+
+    let [user_id, csrf_token] = read_fields(payload, ["user_id", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let user = match data.users.delete(user_id.parse::<u64>().unwrap_or(0)) {
+        Some(user) => user,
+        None => return Ok(redirect_to_users("unknown_user")),
+    };
+    data.sessions.destroy_user(user.id);
+
+    // the uploads stay, but no longer belong to anyone, so a later account with the
+    // same id cannot take them over
+    let mut pastas = data.pastas.lock().unwrap();
+    for i in 0..pastas.len() {
+        if pastas[i].owner == Some(user.id) {
+            pastas[i].owner = None;
+            update(Some(&pastas), Some(&pastas[i]));
+        }
+    }
+    log::info!("Removed user {}.", user.username);
+
+    Ok(redirect_to_users("removed"))
+}
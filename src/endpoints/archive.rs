@@ -1,9 +1,11 @@
 use crate::args::ARGS;
+use crate::util::access;
 use crate::util::animalnumbers::to_u64;
+use crate::util::files_origin::{self, FileGrant};
 use crate::util::hashids::to_u64 as hashid_to_u64;
 use crate::util::misc::remove_expired;
 use crate::AppState;
-use actix_web::{get, web, Error, HttpResponse};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::PathBuf;
@@ -12,16 +14,29 @@ use std::io::Seek;
 
 #[get("/archive/{id}")]
 pub async fn get_archive(
+    request: HttpRequest,
     id: web::Path<String>,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
+    if let Some(denied) = access::denied_for(&data, &request, &id) {
+        return Ok(denied);
+    }
+
+    if files_origin::should_redirect(&request) {
+        return Ok(FileGrant::archive(&id).redirect());
+    }
+
+    archive_response(&data, &id)
+}
+
+pub fn archive_response(data: &AppState, id: &str) -> Result<HttpResponse, Error> {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
     let id_intern = if ARGS.hash_ids {
-        hashid_to_u64(&id).unwrap_or(0)
+        hashid_to_u64(id).unwrap_or(0)
     } else {
-        to_u64(&id.into_inner()).unwrap_or(0)
+        to_u64(id).unwrap_or(0)
     };
 
     // remove expired pastas (including this one if needed)
@@ -1,5 +1,7 @@
 use crate::args::{Args, ARGS};
-use actix_web::{get, web, HttpResponse};
+use crate::util::csrf;
+use crate::AppState;
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use askama::Template;
 
 #[derive(Template)]
@@ -7,14 +9,18 @@ use askama::Template;
 struct AuthAdmin<'a> {
     args: &'a Args,
     status: String,
+    two_factor: bool,
+    csrf_token: String,
 }
 
 #[get("/auth_admin")]
-pub async fn auth_admin() -> HttpResponse {
+pub async fn auth_admin(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         AuthAdmin {
             args: &ARGS,
             status: String::from(""),
+            two_factor: data.two_factor.is_enabled(),
+            csrf_token: csrf::token(&req),
         }
         .render()
         .unwrap(),
@@ -22,13 +28,19 @@ pub async fn auth_admin() -> HttpResponse {
 }
 
 #[get("/auth_admin/{status}")]
-pub async fn auth_admin_with_status(param: web::Path<String>) -> HttpResponse {
+pub async fn auth_admin_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     let status = param.into_inner();
 
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         AuthAdmin {
             args: &ARGS,
             status,
+            two_factor: data.two_factor.is_enabled(),
+            csrf_token: csrf::token(&req),
         }
         .render()
         .unwrap(),
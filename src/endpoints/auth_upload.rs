@@ -1,10 +1,12 @@
 use crate::args::{Args, ARGS};
 use crate::endpoints::errors::ErrorTemplate;
 use crate::util::animalnumbers::to_u64;
+use crate::util::csrf;
 use crate::util::hashids::to_u64 as hashid_to_u64;
 use crate::util::misc::remove_expired;
+use crate::util::security_headers;
 use crate::AppState;
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use askama::Template;
 
 #[derive(Template)]
@@ -16,10 +18,16 @@ struct AuthPasta<'a> {
     encrypted_key: String,
     encrypt_client: bool,
     path: String,
+    csrf_token: String,
+    nonce: String,
 }
 
 #[get("/auth/{id}")]
-pub async fn auth_upload(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+pub async fn auth_upload(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -41,6 +49,8 @@ pub async fn auth_upload(data: web::Data<AppState>, id: web::Path<String>) -> Ht
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("upload"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -57,6 +67,7 @@ pub async fn auth_upload(data: web::Data<AppState>, id: web::Path<String>) -> Ht
 pub async fn auth_upload_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -81,6 +92,8 @@ pub async fn auth_upload_with_status(
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("upload"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -94,7 +107,11 @@ pub async fn auth_upload_with_status(
 }
 
 #[get("/auth_raw/{id}")]
-pub async fn auth_raw_pasta(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+pub async fn auth_raw_pasta(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -116,6 +133,8 @@ pub async fn auth_raw_pasta(data: web::Data<AppState>, id: web::Path<String>) ->
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("raw"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -132,6 +151,7 @@ pub async fn auth_raw_pasta(data: web::Data<AppState>, id: web::Path<String>) ->
 pub async fn auth_raw_pasta_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -156,6 +176,8 @@ pub async fn auth_raw_pasta_with_status(
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("raw"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -169,7 +191,11 @@ pub async fn auth_raw_pasta_with_status(
 }
 
 #[get("/auth_edit_private/{id}")]
-pub async fn auth_edit_private(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+pub async fn auth_edit_private(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -191,6 +217,8 @@ pub async fn auth_edit_private(data: web::Data<AppState>, id: web::Path<String>)
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("edit_private"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -207,6 +235,7 @@ pub async fn auth_edit_private(data: web::Data<AppState>, id: web::Path<String>)
 pub async fn auth_edit_private_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -231,6 +260,8 @@ pub async fn auth_edit_private_with_status(
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("edit_private"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -244,7 +275,11 @@ pub async fn auth_edit_private_with_status(
 }
 
 #[get("/auth_file/{id}")]
-pub async fn auth_file(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+pub async fn auth_file(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -266,6 +301,8 @@ pub async fn auth_file(data: web::Data<AppState>, id: web::Path<String>) -> Http
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("secure_file"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -282,6 +319,7 @@ pub async fn auth_file(data: web::Data<AppState>, id: web::Path<String>) -> Http
 pub async fn auth_file_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -306,6 +344,8 @@ pub async fn auth_file_with_status(
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("secure_file"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -319,7 +359,11 @@ pub async fn auth_file_with_status(
 }
 
 #[get("/auth_remove_private/{id}")]
-pub async fn auth_remove_private(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+pub async fn auth_remove_private(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -341,6 +385,8 @@ pub async fn auth_remove_private(data: web::Data<AppState>, id: web::Path<String
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("remove"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -357,6 +403,7 @@ pub async fn auth_remove_private(data: web::Data<AppState>, id: web::Path<String
 pub async fn auth_remove_private_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -381,6 +428,8 @@ pub async fn auth_remove_private_with_status(
                     encrypted_key: pasta.encrypted_key.to_owned().unwrap_or_default(),
                     encrypt_client: pasta.encrypt_client,
                     path: String::from("remove"),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
                 }
                 .render()
                 .unwrap(),
@@ -1,14 +1,24 @@
+use crate::endpoints::errors::ErrorTemplate;
 use crate::pasta::{Pasta, PastaFile};
-use crate::util::animalnumbers::to_animal_names;
+use crate::util::access;
+use crate::util::animalnumbers::{to_animal_names, to_u64};
+use crate::util::attempts;
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::crypto::StreamEncryptor;
 use crate::util::db::insert;
-use crate::util::hashids::to_hashids;
-use crate::util::misc::{encrypt, encrypt_file, is_valid_url};
+use crate::util::expiry::{
+    default_expiration, expiration_to_timestamp, expiry_presets, max_expiry, ExpiryPreset,
+};
+use crate::util::hashids::{to_hashids, to_u64 as hashid_to_u64};
+use crate::util::manage;
+use crate::util::misc::{encrypt, encrypt_file, is_valid_url, remove_expired};
+use crate::util::recent;
+use crate::util::security_headers;
 use crate::args::{Args, ARGS};
 use crate::AppState;
 use actix_multipart::Multipart;
-use actix_web::error::ErrorBadRequest;
-use actix_web::cookie::Cookie;
-use actix_web::{get, web, Error, HttpResponse, Responder};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse, Responder};
 use askama::Template;
 use bytes::BytesMut;
 use bytesize::ByteSize;
@@ -24,17 +34,28 @@ struct IndexTemplate<'a> {
     args: &'a Args,
     status: String,
     default_privacy_value: String,
-    max_expiry_index: usize,
+    expiry_presets: Vec<ExpiryPreset>,
+    allow_never: bool,
+    fork: Option<&'a Pasta>,
+    // whether uploads can be limited to those who signed in
+    login_available: bool,
+    csrf_token: String,
+    nonce: String,
 }
 
 #[get("/")]
-pub async fn index() -> impl Responder {
+pub async fn index(req: HttpRequest) -> impl Responder {
     HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
         IndexTemplate {
             args: &ARGS,
             status: String::from(""),
             default_privacy_value: ARGS.default_privacy.as_ref().map_or_else(|| String::from("public"), |s| s.clone()),
-            max_expiry_index: ARGS.max_expiry_index(),
+            expiry_presets: expiry_presets(),
+            allow_never: ARGS.eternal_pasta && max_expiry().is_none(),
+            fork: None,
+            login_available: access::login_available(),
+            csrf_token: csrf::token(&req),
+            nonce: security_headers::nonce(&req),
         }
         .render()
         .unwrap(),
@@ -42,7 +63,7 @@ pub async fn index() -> impl Responder {
 }
 
 #[get("/{status}")]
-pub async fn index_with_status(param: web::Path<String>) -> HttpResponse {
+pub async fn index_with_status(param: web::Path<String>, req: HttpRequest) -> HttpResponse {
     let status = param.into_inner();
 
     return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
@@ -50,68 +71,152 @@ pub async fn index_with_status(param: web::Path<String>) -> HttpResponse {
             args: &ARGS,
             status,
             default_privacy_value: ARGS.default_privacy.as_ref().map_or_else(|| String::from("public"), |s| s.clone()),
-            max_expiry_index: ARGS.max_expiry_index(),
+            expiry_presets: expiry_presets(),
+            allow_never: ARGS.eternal_pasta && max_expiry().is_none(),
+            fork: None,
+            login_available: access::login_available(),
+            csrf_token: csrf::token(&req),
+            nonce: security_headers::nonce(&req),
         }
         .render()
         .unwrap(),
     );
 }
 
-const EXPIRATION_OPTIONS: &[&str] = &[
-    "1min",
-    "10min",
-    "1hour",
-    "24hour",
-    "3days",
-    "1week",
-    "1month",
-    "6months",
-    "1year",
-    "2years",
-    "4years",
-    "8years",
-    "16years",
-    "never",
-];
-
-pub fn expiration_to_timestamp(expiration: &str, timenow: i64) -> i64 {
-    match expiration {
-        "1min" => timenow + 60,
-        "10min" => timenow + 60 * 10,
-        "1hour" => timenow + 60 * 60,
-        "24hour" => timenow + 60 * 60 * 24,
-        "3days" => timenow + 60 * 60 * 24 * 3,
-        "1week" => timenow + 60 * 60 * 24 * 7,
-        "1month" => timenow + 60 * 60 * 24 * 30,
-        "6months" => timenow + 60 * 60 * 24 * 30 * 6,
-        "1year" => timenow + 60 * 60 * 24 * 365,
-        "2years" => timenow + 60 * 60 * 24 * 365 * 2,
-        "4years" => timenow + 60 * 60 * 24 * 365 * 4,
-        "8years" => timenow + 60 * 60 * 24 * 365 * 8,
-        "16years" => timenow + 60 * 60 * 24 * 365 * 16,
-        "never" => {
-            if ARGS.eternal_pasta {
-                0
-            } else {
-                timenow + 60 * 60 * 24 * 7
+#[get("/fork/{id}")]
+pub async fn get_fork(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id.into_inner()).unwrap_or(0)
+    };
+
+    remove_expired(&mut pastas);
+
+    for pasta in pastas.iter() {
+        if pasta.id == id {
+            if let Err(denied) = access::check(&data, &req, pasta) {
+                return access::denied_response(&req, denied);
             }
-        }
-        _ => {
-            log::error!("{}", "Unexpected expiration time!");
-            timenow + 60 * 60 * 24 * 7
+
+            if !pasta.forkable() {
+                return HttpResponse::Found()
+                    .append_header((
+                        "Location",
+                        format!("{}/upload/{}", ARGS.public_path_as_str(), pasta.id_as_animals()),
+                    ))
+                    .finish();
+            }
+
+            return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+                IndexTemplate {
+                    args: &ARGS,
+                    status: String::from(""),
+                    default_privacy_value: ARGS.default_privacy.as_ref().map_or_else(|| String::from("public"), |s| s.clone()),
+                    expiry_presets: expiry_presets(),
+                    allow_never: ARGS.eternal_pasta && max_expiry().is_none(),
+                    fork: Some(pasta),
+                    login_available: access::login_available(),
+                    csrf_token: csrf::token(&req),
+                    nonce: security_headers::nonce(&req),
+                }
+                .render()
+                .unwrap(),
+            );
         }
     }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
 }
 
-pub fn is_valid_expiration(expiration: &str, max_expiry: &str) -> bool {
-    let max_index = EXPIRATION_OPTIONS.iter().position(|&x| x == max_expiry).unwrap_or(5);
-    let current_index = EXPIRATION_OPTIONS.iter().position(|&x| x == expiration).unwrap_or(0);
-    current_index <= max_index
+// records the parent of a forked pasta and copies over the files of the parent
+// that were kept selected, unless a file with the same name was uploaded
+fn fork_files(
+    data: &AppState,
+    req: &HttpRequest,
+    new_pasta: &mut Pasta,
+    parent: &str,
+    selected: &[String],
+) -> Result<(), Error> {
+    let parent_id = if ARGS.hash_ids {
+        hashid_to_u64(parent).unwrap_or(0)
+    } else {
+        to_u64(parent).unwrap_or(0)
+    };
+
+    let (parent_slug, parent_files) = {
+        let pastas = data.pastas.lock().unwrap();
+        match pastas
+            .iter()
+            .find(|p| p.id == parent_id && p.forkable() && access::check(data, req, p).is_ok())
+        {
+            Some(p) => (
+                p.id_as_animals(),
+                p.all_files().into_iter().cloned().collect::<Vec<PastaFile>>(),
+            ),
+            // the parent is gone, keep what was submitted as a regular upload
+            None => return Ok(()),
+        }
+    };
+    new_pasta.parent = Some(parent_id);
+
+    if ARGS.no_file_upload {
+        return Ok(());
+    }
+
+    let mut files: Vec<PastaFile> = parent_files
+        .into_iter()
+        .filter(|f| selected.contains(&f.name))
+        .filter(|f| !new_pasta.all_files().iter().any(|uploaded| uploaded.name == f.name))
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    if new_pasta.encrypt_client || new_pasta.encrypt_fragment {
+        return Err(ErrorBadRequest(
+            "Files of the original upload cannot be copied into a client-side encrypted upload.",
+        ));
+    }
+
+    let dir = format!("{}/attachments/{}", ARGS.data_dir, new_pasta.id_as_animals());
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log::error!("Failed to create directory: {}", e);
+        return Err(ErrorInternalServerError("Failed to create attachment directory"));
+    }
+
+    for file in files.iter() {
+        if new_pasta.encrypt_server
+            && file.size.as_u64() > ARGS.max_file_size_encrypted_mb as u64 * 1024 * 1024
+        {
+            return Err(ErrorBadRequest("File exceeded size limit."));
+        }
+        let source = format!("{}/attachments/{}/{}", ARGS.data_dir, parent_slug, file.name());
+        if let Err(e) = std::fs::copy(&source, format!("{}/{}", dir, file.name())) {
+            log::error!("Failed to copy {} of forked upload: {}", source, e);
+            return Err(ErrorInternalServerError("Failed to copy attachment"));
+        }
+    }
+
+    files.extend(new_pasta.all_files().into_iter().cloned());
+    new_pasta.set_files(files);
+    new_pasta.pasta_type = String::from("text");
+
+    Ok(())
 }
 
 pub async fn create(
     data: web::Data<AppState>,
     mut payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(n) => n.as_secs(),
@@ -121,6 +226,8 @@ pub async fn create(
         }
     } as i64;
 
+    let (manage_token, manage_token_hash) = manage::new_token();
+
     let mut new_pasta = Pasta {
         id: rand::thread_rng().gen::<u16>() as u64,
         content: String::from(""),
@@ -137,19 +244,45 @@ pub async fn create(
         burn_after_reads: 0,
         last_read: timenow,
         pasta_type: String::from(""),
-        expiration: expiration_to_timestamp(&ARGS.default_expiry, timenow),
+        expiration: default_expiration(timenow),
         attachments: None,
+        version: 0,
+        parent: None,
+        encrypt_fragment: false,
+        owner: data.sessions.user(&req),
+        manage_token_hash: Some(manage_token_hash),
+        shares: Vec::new(),
+        allowed_networks: Vec::new(),
+        require_login: false,
     };
 
     let mut random_key: String = String::from("");
     let mut plain_key: String = String::from("");
     let mut uploader_password = String::from("");
+    let mut fork_of = String::from("");
+    let mut fork_selection: Vec<String> = Vec::new();
+    let mut encrypted_files: Vec<String> = Vec::new();
+    // the token is the first field of the form, so nothing is stored for forged requests
+    let mut csrf_verified = false;
 
     while let Some(mut field) = payload.try_next().await? {
         let Some(field_name) = field.name() else {
             continue;
         };
+        if field_name != CSRF_FIELD && !csrf_verified {
+            csrf::verify(&req, "")?;
+            csrf_verified = true;
+        }
         match field_name {
+            CSRF_FIELD => {
+                let mut csrf_token = String::new();
+                while let Some(chunk) = field.try_next().await? {
+                    csrf_token.push_str(std::str::from_utf8(&chunk).unwrap_or_default());
+                }
+                csrf::verify(&req, &csrf_token)?;
+                csrf_verified = true;
+                continue;
+            }
             "uploader_password" => {
                 while let Some(chunk) = field.try_next().await? {
                     uploader_password
@@ -157,6 +290,20 @@ pub async fn create(
                 }
                 continue;
             }
+            "fork_of" => {
+                while let Some(chunk) = field.try_next().await? {
+                    fork_of.push_str(std::str::from_utf8(&chunk).unwrap());
+                }
+                continue;
+            }
+            "fork_file" => {
+                let mut name = String::new();
+                while let Some(chunk) = field.try_next().await? {
+                    name.push_str(std::str::from_utf8(&chunk).unwrap());
+                }
+                fork_selection.push(name);
+                continue;
+            }
             "random_key" => {
                 while let Some(chunk) = field.try_next().await? {
                     random_key = std::str::from_utf8(&chunk).unwrap().to_string();
@@ -183,6 +330,8 @@ pub async fn create(
                         "secret" => true,
                         _ => false,
                     };
+                    new_pasta.encrypt_fragment =
+                        privacy == "link" && ARGS.encryption_client_side;
                 }
             }
             "plain_key" => {
@@ -204,11 +353,8 @@ pub async fn create(
                     expiration_str = std::str::from_utf8(&chunk).unwrap().to_string();
                 }
 
-                if !is_valid_expiration(&expiration_str, &ARGS.max_expiry) {
-                    return Err(ErrorBadRequest("Expiration exceeds maximum allowed"));
-                }
-
-                new_pasta.expiration = expiration_to_timestamp(&expiration_str, timenow);
+                new_pasta.expiration =
+                    expiration_to_timestamp(&expiration_str, timenow).map_err(ErrorBadRequest)?;
                 continue;
             }
             "burn_after" => {
@@ -229,6 +375,26 @@ pub async fn create(
 
                 continue;
             }
+            "allowed_networks" => {
+                let mut networks = String::new();
+                while let Some(chunk) = field.try_next().await? {
+                    networks.push_str(std::str::from_utf8(&chunk).unwrap_or_default());
+                }
+
+                new_pasta.allowed_networks =
+                    access::parse_networks(&networks).map_err(ErrorBadRequest)?;
+                continue;
+            }
+            "require_login" => {
+                while let Some(chunk) = field.try_next().await? {
+                    new_pasta.require_login = std::str::from_utf8(&chunk).unwrap_or_default() == "on";
+                }
+                // nobody could see it where nobody can sign in
+                if !access::login_available() {
+                    new_pasta.require_login = false;
+                }
+                continue;
+            }
             "content" => {
                 let mut buf = BytesMut::new();
                 while let Some(chunk) = field.try_next().await? {
@@ -283,14 +449,36 @@ pub async fn create(
                     ));
                 }
 
-                let filepath = format!(
+                let mut filepath = format!(
                     "{}/attachments/{}/{}",
                     ARGS.data_dir,
                     &new_pasta.id_as_animals(),
                     &file.name()
                 );
 
+                // if the key is already known, the file is encrypted while it is
+                // received and its plaintext never touches the disk
+                let key = if new_pasta.encrypt_client {
+                    random_key.clone()
+                } else {
+                    plain_key.clone()
+                };
+                let mut encryptor = None;
+                if new_pasta.encrypt_server && !new_pasta.readonly && key != *"" {
+                    encryptor = Some(
+                        web::block(move || StreamEncryptor::new(&key))
+                            .await?
+                            .map_err(ErrorInternalServerError)?,
+                    );
+                    filepath = format!("{}.enc", filepath);
+                }
+
                 let mut f = web::block(|| std::fs::File::create(filepath)).await??;
+                if let Some(encryptor) = &encryptor {
+                    let header = encryptor.header().to_vec();
+                    f = web::block(move || f.write_all(&header).map(|_| f)).await??;
+                }
+
                 let mut size = 0;
                 while let Some(chunk) = field.try_next().await? {
                     size += chunk.len();
@@ -300,9 +488,22 @@ pub async fn create(
                     {
                         return Err(ErrorBadRequest("File exceeded size limit."));
                     }
+                    let chunk = match encryptor.as_mut() {
+                        Some(encryptor) => encryptor
+                            .update(&chunk)
+                            .map_err(ErrorInternalServerError)?
+                            .into(),
+                        None => chunk,
+                    };
                     f = web::block(move || f.write_all(&chunk).map(|_| f)).await??;
                 }
 
+                if let Some(encryptor) = encryptor {
+                    let last = encryptor.finish().map_err(ErrorInternalServerError)?;
+                    web::block(move || f.write_all(&last)).await??;
+                    encrypted_files.push(file.name().to_string());
+                }
+
                 file.size = ByteSize::b(size as u64);
 
                 if new_pasta.file.is_none() {
@@ -323,12 +524,28 @@ pub async fn create(
     }
 
     if ARGS.readonly && ARGS.uploader_password.is_some() {
-        if uploader_password.trim() != ARGS.uploader_password.as_ref().unwrap().trim() {
-            log::warn!("Uploader password mismatch. Input length: {}, Expected length: {}", uploader_password.trim().len(), ARGS.uploader_password.as_ref().unwrap().trim().len());
+        let subjects = [
+            attempts::client_subject(&req),
+            attempts::UPLOADER_SUBJECT.to_string(),
+        ];
+        if data.attempts.is_locked(&subjects) {
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", format!("{}/locked", ARGS.public_path_as_str())))
+                .finish());
+        }
+
+        if !ARGS.uploader_password.as_ref().unwrap().verify(uploader_password.trim()) {
+            log::warn!("Uploader password mismatch.");
+            data.attempts.record_failure(&subjects);
             return Ok(HttpResponse::Found()
                 .append_header(("Location", format!("{}/incorrect", ARGS.public_path_as_str())))
                 .finish());
         }
+        data.attempts.record_success(attempts::UPLOADER_SUBJECT);
+    }
+
+    if fork_of != *"" {
+        fork_files(&data, &req, &mut new_pasta, &fork_of, &fork_selection)?;
     }
 
     let id = new_pasta.id;
@@ -357,6 +574,9 @@ pub async fn create(
         }
 
         for file in files_to_encrypt {
+            if encrypted_files.iter().any(|name| name == file.name()) {
+                continue;
+            }
              let filepath = format!(
                 "{}/attachments/{}/{}",
                 ARGS.data_dir,
@@ -390,32 +610,23 @@ pub async fn create(
         to_animal_names(id)
     };
 
+    // remembered for the /mine page, and so that viewing it right away is not a read
+    let recent_cookie = recent::cookie_with(&req, id, timenow);
+
     if encrypt_server {
         Ok(HttpResponse::Found()
             .append_header(("Location", format!("{}/auth/{}/success", ARGS.public_path_as_str(), slug)))
+            .cookie(recent_cookie)
+            .cookie(manage::flash_cookie(&slug, &manage_token))
             .finish())
     } else {
-        // Generate time-limited token for initial view using Hashids
-        let timenow = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let expiry = timenow + 15; // 15 seconds validity
-        
-        // Use global HARSH instance
-        let encoded_token = crate::util::hashids::HARSH.encode(&[expiry, id]);
-
         Ok(HttpResponse::Found()
             .append_header((
                 "Location",
                 format!("{}/upload/{}", ARGS.public_path_as_str(), slug),
             ))
-            .cookie(
-                Cookie::build("owner_token", encoded_token)
-                    .path("/")
-                    .max_age(actix_web::cookie::time::Duration::seconds(15))
-                    .finish(),
-            )
+            .cookie(recent_cookie)
+            .cookie(manage::flash_cookie(&slug, &manage_token))
             .finish())
     }
 }
@@ -1,16 +1,29 @@
 use crate::args::Args;
 use crate::endpoints::errors::ErrorTemplate;
+use crate::util::access;
 use crate::util::animalnumbers::to_u64;
+use crate::util::attempts;
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::auth::{read_fields, same_password};
 use crate::util::db::update;
 use crate::util::hashids::to_u64 as hashid_to_u64;
-use crate::util::misc::{decrypt, encrypt, remove_expired};
+use crate::util::manage;
+use crate::pasta::PastaFile;
+use crate::util::crypto;
+use crate::util::misc::{decrypt, encrypt, remove_expired, unlocked_copy};
 use crate::{AppState, Pasta, ARGS};
 use actix_multipart::Multipart;
-use actix_web::error::ErrorBadRequest;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use askama::Template;
 use bytes::BytesMut;
+use bytesize::ByteSize;
 use futures::TryStreamExt;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use tempfile::NamedTempFile;
 
 #[derive(Template)]
 #[template(path = "edit.html", escape = "none")]
@@ -19,23 +32,417 @@ struct EditTemplate<'a> {
     args: &'a Args,
     path: &'a String,
     status: &'a String,
+    needs_password: bool,
+    manage_token: &'a str,
+    csrf_token: String,
 }
 
-#[get("/edit/{id}")]
-pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+#[derive(Template)]
+#[template(path = "edit_conflict.html")]
+struct EditConflictTemplate<'a> {
+    pasta: &'a Pasta,
+    args: &'a Args,
+    path: &'a String,
+    current_content: &'a String,
+    submitted_content: &'a String,
+    needs_password: bool,
+    manage_token: &'a str,
+    csrf_token: String,
+}
+
+// renders the page shown when the upload was changed by someone else since the
+// editor was opened. `current_content` must already be decrypted.
+fn conflict_response(
+    req: &HttpRequest,
+    pasta: &Pasta,
+    path: &str,
+    current_content: &String,
+    submitted_content: &String,
+    needs_password: bool,
+    manage_token: &str,
+) -> HttpResponse {
+    HttpResponse::Conflict()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            EditConflictTemplate {
+                pasta,
+                args: &ARGS,
+                path: &String::from(path),
+                current_content,
+                submitted_content,
+                needs_password,
+                manage_token,
+                csrf_token: csrf::token(req),
+            }
+            .render()
+            .unwrap(),
+        )
+}
+
+// reads the version counter the editor was opened with, None if the client did not send one
+async fn read_version(field: &mut actix_multipart::Field) -> Result<Option<u64>, Error> {
+    let mut version = String::new();
+    while let Some(chunk) = field.try_next().await? {
+        version.push_str(std::str::from_utf8(&chunk).unwrap_or_default());
+    }
+    Ok(version.trim().parse::<u64>().ok())
+}
+
+// the fields submitted from the editor
+struct EditForm {
+    csrf_token: String,
+    content: String,
+    password: String,
+    manage_token: String,
+    version: Option<u64>,
+    // names of the files as they were listed in the editor, keyed by position
+    file_names: BTreeMap<usize, String>,
+    renames: BTreeMap<usize, String>,
+    removals: BTreeSet<usize>,
+    // newly uploaded files, staged next to the attachments until the edit is applied
+    uploads: Vec<(PastaFile, NamedTempFile)>,
+}
+
+impl EditForm {
+    fn changes_files(&self) -> bool {
+        !self.uploads.is_empty()
+            || !self.removals.is_empty()
+            || self
+                .renames
+                .iter()
+                .any(|(i, name)| !name.trim().is_empty() && self.file_names.get(i) != Some(name))
+    }
+}
+
+// parses the indexed form fields of the attachment list, e.g. "rename_2"
+fn field_index(name: &str, prefix: &str) -> Option<usize> {
+    name.strip_prefix(prefix)?.parse::<usize>().ok()
+}
+
+async fn read_text(field: &mut actix_multipart::Field) -> Result<String, Error> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = field.try_next().await? {
+        buf.extend_from_slice(&chunk);
+    }
+    String::from_utf8(buf.to_vec()).map_err(|_| ErrorBadRequest("Invalid UTF-8 in form field"))
+}
+
+async fn read_edit_form(payload: &mut Multipart) -> Result<EditForm, Error> {
+    let mut form = EditForm {
+        csrf_token: String::from(""),
+        content: String::from(""),
+        password: String::from(""),
+        manage_token: String::from(""),
+        version: None,
+        file_names: BTreeMap::new(),
+        renames: BTreeMap::new(),
+        removals: BTreeSet::new(),
+        uploads: Vec::new(),
+    };
+
+    while let Some(mut field) = payload.try_next().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            CSRF_FIELD => form.csrf_token = read_text(&mut field).await?,
+            "version" => form.version = read_version(&mut field).await?,
+            "content" => form.content = read_text(&mut field).await?,
+            "password" => form.password = read_text(&mut field).await?,
+            "manage_token" => form.manage_token = read_text(&mut field).await?,
+            "file" => {
+                if ARGS.no_file_upload {
+                    continue;
+                }
+
+                let file = match field.content_disposition().and_then(|cd| cd.get_filename()) {
+                    Some("") | None => continue,
+                    Some(path) => match PastaFile::from_unsanitized(path) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            warn!("Unsafe file name: {e:?}");
+                            continue;
+                        }
+                    },
+                };
+
+                let staging_dir = format!("{}/attachments", ARGS.data_dir);
+                if let Err(e) = std::fs::create_dir_all(&staging_dir) {
+                    log::error!("Failed to create directory: {}", e);
+                    return Err(ErrorInternalServerError(
+                        "Failed to create attachment directory",
+                    ));
+                }
+                let mut tmp = web::block(move || {
+                    tempfile::Builder::new()
+                        .prefix(".upload-")
+                        .tempfile_in(staging_dir)
+                })
+                .await??;
+
+                let mut size = 0;
+                while let Some(chunk) = field.try_next().await? {
+                    size += chunk.len();
+                    if size > ARGS.max_file_size_unencrypted_mb * 1024 * 1024 {
+                        return Err(ErrorBadRequest("File exceeded size limit."));
+                    }
+                    tmp = web::block(move || tmp.write_all(&chunk).map(|_| tmp)).await??;
+                }
+
+                form.uploads.push((
+                    PastaFile {
+                        name: file.name,
+                        size: ByteSize::b(size as u64),
+                    },
+                    tmp,
+                ));
+            }
+            name => {
+                if let Some(i) = field_index(name, "file_") {
+                    form.file_names.insert(i, read_text(&mut field).await?);
+                } else if let Some(i) = field_index(name, "rename_") {
+                    form.renames.insert(i, read_text(&mut field).await?);
+                } else if let Some(i) = field_index(name, "remove_") {
+                    read_text(&mut field).await?;
+                    form.removals.insert(i);
+                } else {
+                    log::error!("Unexpected multipart field:  {}", name);
+                }
+            }
+        }
+    }
+
+    Ok(form)
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    log::error!("Failed to update attachments: {}", e);
+    ErrorInternalServerError("Failed to update attachments")
+}
+
+// changes to the files of a pasta, prepared on a copy of it while the lock is released.
+// New files are written and encrypted next to the attachments already; applying the
+// changes only renames files.
+struct AttachmentChanges {
+    files: Vec<PastaFile>,
+    dir: String,
+    // (current path, new path) of stored files that get another name
+    renames: Vec<(String, String)>,
+    // uploaded files and the paths they are stored at
+    uploads: Vec<(NamedTempFile, String)>,
+    // files nothing refers to once the changes are applied
+    obsolete: Vec<String>,
+}
+
+// prepares the removals, renames and uploads of the form for the files of the pasta.
+// `key` is the password the files of server-side encrypted pastas are encrypted with.
+// Returns None if the form changes no file.
+fn prepare_attachment_changes(
+    pasta: &Pasta,
+    form: EditForm,
+    key: Option<&str>,
+) -> Result<Option<AttachmentChanges>, Error> {
+    if !form.changes_files() {
+        return Ok(None);
+    }
+
+    if pasta.encrypt_server && key.is_none() {
+        return Err(ErrorBadRequest(
+            "The files of this upload can only be changed with its password.",
+        ));
+    }
+
+    // only act on positions whose name still matches what the editor listed
+    let listed = |i: usize, file: &PastaFile| form.file_names.get(&i) == Some(&file.name);
+
+    let mut removed: Vec<PastaFile> = Vec::new();
+    // (file as currently stored, name it will have afterwards)
+    let mut kept: Vec<(PastaFile, String)> = Vec::new();
+    for (i, file) in pasta.all_files().into_iter().enumerate() {
+        if listed(i, file) && form.removals.contains(&i) {
+            removed.push(file.clone());
+            continue;
+        }
+
+        let mut new_name = file.name.clone();
+        if let Some(rename) = form.renames.get(&i).filter(|_| listed(i, file)) {
+            let rename = rename.trim();
+            if !rename.is_empty() && rename != file.name {
+                new_name = PastaFile::from_unsanitized(rename)
+                    .map_err(ErrorBadRequest)?
+                    .name;
+            }
+        }
+        kept.push((file.clone(), new_name));
+    }
+
+    // an upload with the name of a kept file replaces its contents
+    let mut replacements: BTreeMap<usize, (PastaFile, NamedTempFile)> = BTreeMap::new();
+    let mut additions: Vec<(PastaFile, NamedTempFile)> = Vec::new();
+    for (file, tmp) in form.uploads {
+        if pasta.encrypt_server && file.size.as_u64() > ARGS.max_file_size_encrypted_mb as u64 * 1024 * 1024 {
+            return Err(ErrorBadRequest("File exceeded size limit."));
+        }
+        match kept.iter().position(|(_, name)| *name == file.name) {
+            Some(i) => {
+                replacements.insert(i, (file, tmp));
+            }
+            None => {
+                additions.retain(|(f, _)| f.name != file.name);
+                additions.push((file, tmp));
+            }
+        }
+    }
+
+    let mut final_names: BTreeSet<&str> = BTreeSet::new();
+    for name in kept
+        .iter()
+        .map(|(_, name)| name.as_str())
+        .chain(additions.iter().map(|(f, _)| f.name.as_str()))
+    {
+        if !final_names.insert(name) {
+            return Err(ErrorBadRequest("Two files would end up with the same name."));
+        }
+    }
+
+    let dir = format!("{}/attachments/{}", ARGS.data_dir, pasta.id_as_animals());
+    let new_path = |name: &str| {
+        if pasta.encrypt_server {
+            format!("{}/{}.enc", dir, name)
+        } else {
+            format!("{}/{}", dir, name)
+        }
+    };
+
+    let mut files: Vec<PastaFile> = Vec::new();
+    let mut renames: Vec<(String, String)> = Vec::new();
+    let mut uploads: Vec<(NamedTempFile, String)> = Vec::new();
+    let mut obsolete: Vec<String> = removed
+        .iter()
+        .map(|file| pasta.stored_file_path(&file.name))
+        .collect();
+    for (i, (file, new_name)) in kept.into_iter().enumerate() {
+        let path = pasta.stored_file_path(&file.name);
+        match replacements.remove(&i) {
+            Some((new_file, tmp)) => {
+                obsolete.push(path);
+                uploads.push((tmp, new_path(&new_file.name)));
+                files.push(new_file);
+            }
+            None => {
+                if new_name != file.name {
+                    renames.push((path, new_path(&new_name)));
+                }
+                files.push(PastaFile {
+                    name: new_name,
+                    size: file.size,
+                });
+            }
+        }
+    }
+    for (file, tmp) in additions {
+        uploads.push((tmp, new_path(&file.name)));
+        files.push(file);
+    }
+
+    // a file that is removed or replaced may leave its name to another one
+    obsolete.retain(|path| {
+        !renames.iter().any(|(_, new_path)| new_path == path)
+            && !uploads.iter().any(|(_, new_path)| new_path == path)
+    });
+
+    // files of server-side encrypted pastas are never stored in plaintext
+    if pasta.encrypt_server {
+        for (tmp, _) in uploads.iter_mut() {
+            let mut sealed = tempfile::Builder::new()
+                .prefix(".upload-")
+                .tempfile_in(format!("{}/attachments", ARGS.data_dir))
+                .map_err(io_error)?;
+            crypto::seal_stream(
+                BufReader::new(tmp.reopen().map_err(io_error)?),
+                BufWriter::new(sealed.as_file_mut()),
+                key.unwrap(),
+            )
+            .map_err(|e| {
+                log::error!("Failed to encrypt file: {}", e);
+                ErrorInternalServerError("Failed to encrypt file")
+            })?;
+            *tmp = sealed;
+        }
+    }
+
+    Ok(Some(AttachmentChanges {
+        files,
+        dir,
+        renames,
+        uploads,
+        obsolete,
+    }))
+}
+
+impl AttachmentChanges {
+    // moves the prepared files into place and lists them on the pasta. Returns the
+    // files to remove once the lock is released.
+    fn apply(self, pasta: &mut Pasta) -> Result<ObsoleteFiles, Error> {
+        // renamed files are moved out of the way first so names can be swapped
+        let mut moved: Vec<(String, String)> = Vec::new();
+        for (i, (path, new_path)) in self.renames.into_iter().enumerate() {
+            let staged = format!("{}/.rename-{}", self.dir, i);
+            fs::rename(&path, &staged).map_err(io_error)?;
+            moved.push((staged, new_path));
+        }
+        for (staged, new_path) in moved {
+            fs::rename(&staged, &new_path).map_err(io_error)?;
+        }
+
+        if !self.uploads.is_empty() {
+            fs::create_dir_all(&self.dir).map_err(io_error)?;
+        }
+        for (tmp, path) in self.uploads {
+            tmp.persist(&path).map_err(|e| io_error(e.error))?;
+        }
+
+        pasta.set_files(self.files);
+        Ok(ObsoleteFiles {
+            paths: self.obsolete,
+            dir: self.dir,
+        })
+    }
+}
+
+// files of a pasta that were removed or replaced, deleted without holding the lock
+struct ObsoleteFiles {
+    paths: Vec<String>,
+    dir: String,
+}
+
+impl ObsoleteFiles {
+    fn remove(self) {
+        for path in self.paths {
+            let _ = fs::remove_file(path);
+        }
+        // only succeeds once the pasta has no files left
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+/// The editor, for the management link of the upload too.
+pub fn edit_page(data: &AppState, req: &HttpRequest, id: &str, manage_token: &str) -> HttpResponse {
     let mut pastas = data.pastas.lock().unwrap();
 
     let id = if ARGS.hash_ids {
-        hashid_to_u64(&id).unwrap_or(0)
+        hashid_to_u64(id).unwrap_or(0)
     } else {
-        to_u64(&id.into_inner()).unwrap_or(0)
+        to_u64(id).unwrap_or(0)
     };
 
     remove_expired(&mut pastas);
 
     for pasta in pastas.iter() {
         if pasta.id == id {
-            if !pasta.editable {
+            if let Err(denied) = access::check(data, req, pasta) {
+                return access::denied_response(req, denied);
+            }
+
+            if !pasta.editable || pasta.encrypt_fragment {
                 return HttpResponse::Found()
                     .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
                     .finish();
@@ -56,6 +463,11 @@ pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpR
                     args: &ARGS,
                     path: &String::from("edit"),
                     status: &String::from(""),
+                    needs_password: pasta.readonly
+                        && !data.sessions.can_manage(req, pasta)
+                        && !manage::token_matches(pasta, manage_token),
+                    manage_token,
+                    csrf_token: csrf::token(req),
                 }
                 .render()
                 .unwrap(),
@@ -68,10 +480,20 @@ pub async fn get_edit(data: web::Data<AppState>, id: web::Path<String>) -> HttpR
         .body(ErrorTemplate { args: &ARGS }.render().unwrap())
 }
 
+#[get("/edit/{id}")]
+pub async fn get_edit(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    edit_page(&data, &req, &id, "")
+}
+
 #[get("/edit/{id}/{status}")]
 pub async fn get_edit_with_status(
     data: web::Data<AppState>,
     param: web::Path<(String, String)>,
+    req: HttpRequest,
 ) -> HttpResponse {
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -87,7 +509,11 @@ pub async fn get_edit_with_status(
 
     for pasta in pastas.iter() {
         if pasta.id == intern_id {
-            if !pasta.editable {
+            if let Err(denied) = access::check(&data, &req, pasta) {
+                return access::denied_response(&req, denied);
+            }
+
+            if !pasta.editable || pasta.encrypt_fragment {
                 return HttpResponse::Found()
                     .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
                     .finish();
@@ -108,6 +534,9 @@ pub async fn get_edit_with_status(
                     args: &ARGS,
                     path: &String::from("edit"),
                     status: &status,
+                    needs_password: pasta.readonly && !data.sessions.can_manage(&req, pasta),
+                    manage_token: "",
+                    csrf_token: csrf::token(&req),
                 }
                 .render()
                 .unwrap(),
@@ -124,7 +553,8 @@ pub async fn get_edit_with_status(
 pub async fn post_edit_private(
     data: web::Data<AppState>,
     id: web::Path<String>,
-    mut payload: Multipart,
+    payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let id = if ARGS.hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
@@ -132,15 +562,8 @@ pub async fn post_edit_private(
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut password = String::from("");
-
-    while let Some(mut field) = payload.try_next().await? {
-        if field.name() == Some("password") {
-            while let Some(chunk) = field.try_next().await? {
-                password.push_str(std::str::from_utf8(&chunk).unwrap().to_string().as_str());
-            }
-        }
-    }
+    let [password, csrf_token] = read_fields(payload, ["password", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
 
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -159,49 +582,63 @@ pub async fn post_edit_private(
         }
     }
 
-    if found && !pastas[index].encrypt_client {
-        let original_content = pastas[index].content.to_owned();
+    if found && !pastas[index].encrypt_client && !pastas[index].encrypt_fragment {
+        let subjects = [
+            attempts::client_subject(&req),
+            attempts::upload_subject(&pastas[index].id_as_animals()),
+        ];
+        if password != "" && data.attempts.is_locked(&subjects) {
+            return Ok(HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!(
+                        "{}/auth_edit_private/{}/locked",
+                        ARGS.public_path_as_str(),
+                        pastas[index].id_as_animals()
+                    ),
+                ))
+                .finish());
+        }
+
+        let mut pasta = unlocked_copy(pastas, index);
 
         // decrypt content temporarily
         if password != "" {
-            let res = decrypt(&original_content, &password);
-            if res.is_ok() {
-                pastas[index]
-                    .content
-                    .replace_range(.., res.unwrap().as_str());
-                // save pasta in database
-                update(Some(&pastas), Some(&pastas[index]));
-            } else {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!(
-                            "{}/auth_edit_private/{}/incorrect",
-                            ARGS.public_path_as_str(),
-                            pastas[index].id_as_animals()
-                        ),
-                    ))
-                    .finish());
+            match decrypt(&pasta.content, &password) {
+                Ok(content) => {
+                    data.attempts.record_success(&subjects[1]);
+                    pasta.content = content;
+                }
+                Err(_) => {
+                    data.attempts.record_failure(&subjects);
+                    return Ok(HttpResponse::Found()
+                        .append_header((
+                            "Location",
+                            format!(
+                                "{}/auth_edit_private/{}/incorrect",
+                                ARGS.public_path_as_str(),
+                                pasta.id_as_animals()
+                            ),
+                        ))
+                        .finish());
+                }
             }
         }
 
         // serve pasta in template
-        let response = HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        return Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
             EditTemplate {
-                pasta: &pastas[index],
+                pasta: &pasta,
                 args: &ARGS,
                 path: &String::from("submit_edit_private"),
                 status: &String::from(""),
+                needs_password: true,
+                manage_token: "",
+                csrf_token: csrf::token(&req),
             }
             .render()
             .unwrap(),
-        );
-
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
-        }
-
-        return Ok(response);
+        ));
     }
     Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
@@ -213,6 +650,7 @@ pub async fn post_submit_edit_private(
     data: web::Data<AppState>,
     id: web::Path<String>,
     mut payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let id = if ARGS.hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
@@ -220,26 +658,10 @@ pub async fn post_submit_edit_private(
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut password = String::from("");
-    let mut new_content = String::from("");
-
-    while let Some(mut field) = payload.try_next().await? {
-        if field.name() == Some("content") {
-            let mut buf = BytesMut::new();
-            while let Some(chunk) = field.try_next().await? {
-                buf.extend_from_slice(&chunk);
-            }
-            if !buf.is_empty() {
-                new_content = String::from_utf8(buf.to_vec())
-                    .map_err(|_| ErrorBadRequest("Invalid UTF-8 in content"))?;
-            }
-        }
-        if field.name() == Some("password") {
-            while let Some(chunk) = field.try_next().await? {
-                password = std::str::from_utf8(&chunk).unwrap().to_string();
-            }
-        }
-    }
+    let form = read_edit_form(&mut payload).await?;
+    csrf::verify(&req, &form.csrf_token)?;
+    let password = form.password.to_owned();
+    let new_content = form.content.to_owned();
 
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -258,53 +680,100 @@ pub async fn post_submit_edit_private(
         }
     }
 
-    if found && pastas[index].editable && !pastas[index].encrypt_client {
-        if pastas[index].readonly {
-            let res = decrypt(pastas[index].encrypted_key.as_ref().unwrap(), &password);
-            if res.is_ok() {
-                pastas[index]
-                    .content
-                    .replace_range(.., &encrypt(&new_content, &password));
-            } else {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-                    ))
-                    .finish());
-            }
-        } else if pastas[index].private {
-            let res = decrypt(&pastas[index].content, &password);
-            if res.is_ok() {
-                pastas[index]
-                    .content
-                    .replace_range(.., &encrypt(&new_content, &password));
-                // save pasta in database
-                update(Some(&pastas), Some(&pastas[index]));
-            } else {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!(
-                            "{}/auth_edit_private/{}/incorrect",
-                            ARGS.public_path_as_str(),
-                            pastas[index].id_as_animals()
-                        ),
-                    ))
-                    .finish());
-            }
-        }
+    if !found || !pastas[index].editable || pastas[index].encrypt_client || pastas[index].encrypt_fragment {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS }.render().unwrap()));
+    }
 
-        return Ok(HttpResponse::Found()
+    let slug = pastas[index].id_as_animals();
+    let success = HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!("{}/auth/{}/success", ARGS.public_path_as_str(), slug),
+        ))
+        .finish();
+    // read-only uploads are unlocked on the editor, private ones on their password page
+    let page = if pastas[index].readonly { "edit" } else { "auth_edit_private" };
+    let redirect = |status: &str| {
+        HttpResponse::Found()
             .append_header((
                 "Location",
-                format!("{}/auth/{}/success", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                format!("{}/{}/{}/{}", ARGS.public_path_as_str(), page, slug, status),
             ))
-            .finish());
+            .finish()
+    };
+
+    let subjects = [attempts::client_subject(&req), attempts::upload_subject(&slug)];
+    if data.attempts.is_locked(&subjects) {
+        return Ok(redirect("locked"));
     }
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+
+    if !pastas[index].readonly && !pastas[index].private {
+        return Ok(success);
+    }
+
+    let original = unlocked_copy(pastas, index);
+
+    let current_content = if original.readonly {
+        decrypt(original.encrypted_key.as_ref().unwrap(), &password).map(|_| original.content.to_owned())
+    } else {
+        decrypt(&original.content, &password)
+    };
+    let current_content = match current_content {
+        Ok(current_content) => current_content,
+        Err(_) => {
+            data.attempts.record_failure(&subjects);
+            return Ok(redirect("incorrect"));
+        }
+    };
+    data.attempts.record_success(&subjects[1]);
+    let encrypted_content = encrypt(&new_content, &password);
+    let version = form.version;
+    // files of private uploads are re-encrypted with the same password as the content
+    let key = Some(password.as_str()).filter(|_| !original.readonly);
+    let changes = prepare_attachment_changes(&original, form, key)?;
+
+    let mut pastas = data.pastas.lock().unwrap();
+    let index = match pastas.iter().position(|pasta| pasta.id == id) {
+        Some(index) if same_password(&pastas[index], &original) => index,
+        Some(_) => return Ok(redirect("incorrect")),
+        None => {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+        }
+    };
+
+    // the files were prepared for the pasta as it was read
+    let changed = pastas[index].version != original.version;
+    if version.is_some_and(|v| v != pastas[index].version) || (changed && changes.is_some()) {
+        // shows the content as it was read, a second save brings up the newest one
+        return Ok(conflict_response(
+            &req,
+            &original,
+            "submit_edit_private",
+            &current_content,
+            &new_content,
+            true,
+            "",
+        ));
+    }
+
+    let obsolete = changes
+        .map(|changes| changes.apply(&mut pastas[index]))
+        .transpose()?;
+    pastas[index].content = encrypted_content;
+    pastas[index].version += 1;
+    // save pasta in database
+    update(Some(&pastas), Some(&pastas[index]));
+    drop(pastas);
+
+    if let Some(obsolete) = obsolete {
+        obsolete.remove();
+    }
+
+    Ok(success)
 }
 
 #[post("/edit/{id}")]
@@ -312,6 +781,7 @@ pub async fn post_edit(
     data: web::Data<AppState>,
     id: web::Path<String>,
     mut payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let id = if ARGS.hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
@@ -319,80 +789,110 @@ pub async fn post_edit(
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut new_content = String::from("");
-    let mut password = String::from("");
+    let form = read_edit_form(&mut payload).await?;
+    csrf::verify(&req, &form.csrf_token)?;
+    let new_content = form.content.to_owned();
 
-    while let Some(mut field) = payload.try_next().await? {
-        if field.name() == Some("content") {
-            let mut buf = BytesMut::new();
-            while let Some(chunk) = field.try_next().await? {
-                buf.extend_from_slice(&chunk);
-            }
-            if !buf.is_empty() {
-                new_content = String::from_utf8(buf.to_vec())
-                    .map_err(|_| ErrorBadRequest("Invalid UTF-8 in content"))?;
-            }
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let mut index = match pastas.iter().position(|pasta| pasta.id == id) {
+        Some(i) if pastas[i].editable && !pastas[i].encrypt_client && !pastas[i].encrypt_fragment => i,
+        _ => {
+            return Ok(HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
         }
-        if field.name() == Some("password") {
-            while let Some(chunk) = field.try_next().await? {
-                password = std::str::from_utf8(&chunk).unwrap().to_string();
-            }
+    };
+
+    // the owner, the admin and the holder of the management link change read-only uploads
+    // without their password
+    let needs_password = pastas[index].encrypt_server
+        || (pastas[index].readonly
+            && !data.sessions.can_manage(&req, &pastas[index])
+            && !manage::token_matches(&pastas[index], &form.manage_token));
+
+    if needs_password {
+        let slug = pastas[index].id_as_animals();
+        let redirect = |status: &str| {
+            HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/edit/{}/{}", ARGS.public_path_as_str(), slug, status),
+                ))
+                .finish()
+        };
+
+        let subjects = [attempts::client_subject(&req), attempts::upload_subject(&slug)];
+        if data.attempts.is_locked(&subjects) {
+            return Ok(redirect("locked"));
         }
-    }
 
-    let mut pastas = data.pastas.lock().unwrap();
+        let original = unlocked_copy(pastas, index);
 
-    remove_expired(&mut pastas);
+        let authorized = !form.password.is_empty()
+            && decrypt(original.encrypted_key.as_ref().unwrap(), &form.password).is_ok();
+        if authorized {
+            data.attempts.record_success(&subjects[1]);
+        } else {
+            data.attempts.record_failure(&subjects);
+            return Ok(redirect("incorrect"));
+        }
 
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            if pasta.editable && !pasta.encrypt_client {
-                if pastas[i].readonly || pastas[i].encrypt_server {
-                    if password != "" {
-                        let res = decrypt(pastas[i].encrypted_key.as_ref().unwrap(), &password);
-                        if res.is_ok() {
-                            pastas[i].content.replace_range(.., &new_content);
-                            // save pasta in database
-                            update(Some(&pastas), Some(&pastas[i]));
-                        } else {
-                            return Ok(HttpResponse::Found()
-                                .append_header((
-                                    "Location",
-                                    format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                                ))
-                                .finish());
-                        }
-                    } else {
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/edit/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                            ))
-                            .finish());
-                    }
-                } else {
-                    pastas[i].content.replace_range(.., &new_content);
-                    // save pasta in database
-                    update(Some(&pastas), Some(&pastas[i]));
-                }
+        pastas = data.pastas.lock().unwrap();
+        index = match pastas.iter().position(|pasta| pasta.id == id) {
+            Some(index) if same_password(&pastas[index], &original) => index,
+            Some(_) => return Ok(redirect("incorrect")),
+            None => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+            }
+        };
+    }
 
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!(
-                            "{}/upload/{}",
-                            ARGS.public_path_as_str(),
-                            pastas[i].id_as_animals()
-                        ),
-                    ))
-                    .finish());
-            } else {
-                break;
+    let manage_token = form.manage_token.to_owned();
+    let conflict = |pasta: &Pasta| {
+        conflict_response(&req, pasta, "edit", &pasta.content, &new_content, needs_password, &manage_token)
+    };
+    if form.version.is_some_and(|v| v != pastas[index].version) {
+        return Ok(conflict(&pastas[index]));
+    }
+
+    let mut changes = None;
+    if form.changes_files() {
+        let original = unlocked_copy(pastas, index);
+        changes = prepare_attachment_changes(&original, form, None)?;
+
+        // the files were prepared for the pasta as it was read
+        pastas = data.pastas.lock().unwrap();
+        index = match pastas.iter().position(|pasta| pasta.id == id) {
+            Some(index) if pastas[index].version == original.version => index,
+            Some(index) => return Ok(conflict(&pastas[index])),
+            None => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
             }
-        }
+        };
     }
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+    let obsolete = changes
+        .map(|changes| changes.apply(&mut pastas[index]))
+        .transpose()?;
+    pastas[index].content.replace_range(.., &new_content);
+    pastas[index].version += 1;
+    // save pasta in database
+    update(Some(&pastas), Some(&pastas[index]));
+    let slug = pastas[index].id_as_animals();
+    drop(pastas);
+
+    if let Some(obsolete) = obsolete {
+        obsolete.remove();
+    }
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/upload/{}", ARGS.public_path_as_str(), slug)))
+        .finish())
 }
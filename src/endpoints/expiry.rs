@@ -0,0 +1,249 @@
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::util::animalnumbers::to_u64;
+use crate::util::attempts;
+use crate::util::auth::{self, read_fields};
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::db::update;
+use crate::util::expiry::{expiration_to_timestamp, expiry_presets, max_expiry, ExpiryPreset};
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::manage;
+use crate::util::misc::{remove_expired, unlocked_copy};
+use crate::util::security_headers;
+use crate::{AppState, Pasta};
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Template)]
+#[template(path = "expiry.html")]
+struct ExpiryTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    status: String,
+    // false for uploads without a password, which only their uploader can change
+    allowed: bool,
+    needs_password: bool,
+    expiry_presets: Vec<ExpiryPreset>,
+    allow_never: bool,
+    manage_token: &'a str,
+    csrf_token: String,
+    nonce: String,
+}
+
+/// The expiry page, for the management link of the upload too.
+pub fn expiry_page(
+    data: &AppState,
+    req: &HttpRequest,
+    id: &str,
+    status: String,
+    manage_token: &str,
+) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    };
+
+    remove_expired(&mut pastas);
+
+    for pasta in pastas.iter() {
+        if pasta.id == id {
+            let managed = data.sessions.can_manage(req, pasta) || manage::token_matches(pasta, manage_token);
+            return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+                ExpiryTemplate {
+                    args: &ARGS,
+                    pasta,
+                    status,
+                    allowed: managed || auth::pasta_needs_password(pasta),
+                    needs_password: auth::pasta_needs_password(pasta) && !managed,
+                    expiry_presets: expiry_presets(),
+                    allow_never: ARGS.eternal_pasta && max_expiry().is_none(),
+                    manage_token,
+                    csrf_token: csrf::token(req),
+                    nonce: security_headers::nonce(req),
+                }
+                .render()
+                .unwrap(),
+            );
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+}
+
+#[get("/expiry/{id}")]
+pub async fn get_expiry(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    expiry_page(&data, &req, &id, String::from(""), "")
+}
+
+#[get("/expiry/{id}/{status}")]
+pub async fn get_expiry_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, status) = param.into_inner();
+    expiry_page(&data, &req, &id, status, "")
+}
+
+#[post("/expiry/{id}")]
+pub async fn post_expiry(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id.into_inner()).unwrap_or(0)
+    };
+
+    let [expiration, password, manage_token, csrf_token] =
+        read_fields(payload, ["expiration", "password", "manage_token", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    } as i64;
+
+    let not_found = || {
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+    };
+
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let mut i = match pastas.iter().position(|pasta| pasta.id == id) {
+        Some(i) => i,
+        None => return Ok(not_found()),
+    };
+    let slug = pastas[i].id_as_animals();
+    let linked = manage::token_matches(&pastas[i], &manage_token);
+    let managed = linked || data.sessions.can_manage(&req, &pastas[i]);
+    let redirect = |status: &str| {
+        HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!("{}/expiry/{}/{}", ARGS.public_path_as_str(), slug, status),
+            ))
+            .finish()
+    };
+
+    // an upload that expires in a minute is as good as removed, so without a
+    // password only the uploader can change it, as for removal
+    if !managed && !auth::pasta_needs_password(&pastas[i]) {
+        return Ok(redirect("forbidden"));
+    }
+
+    if !managed {
+        let subjects = [attempts::client_subject(&req), attempts::upload_subject(&slug)];
+        if data.attempts.is_locked(&subjects) {
+            return Ok(redirect("locked"));
+        }
+
+        let original = unlocked_copy(pastas, i);
+        if !auth::pasta_password_matches(&original, &password) {
+            data.attempts.record_failure(&subjects);
+            return Ok(redirect("incorrect"));
+        }
+        data.attempts.record_success(&subjects[1]);
+
+        pastas = data.pastas.lock().unwrap();
+        i = match pastas.iter().position(|pasta| pasta.id == id) {
+            Some(i) if auth::same_password(&pastas[i], &original) => i,
+            Some(_) => return Ok(redirect("incorrect")),
+            None => return Ok(not_found()),
+        };
+    }
+
+    match expiration_to_timestamp(&expiration, timenow) {
+        Ok(new_expiration) => pastas[i].expiration = new_expiration,
+        Err(e) => {
+            log::warn!("Rejected expiry change of {}: {}", slug, e);
+            let location = if linked {
+                format!("{}/expiry/invalid", manage::url(&slug, &manage_token))
+            } else {
+                format!("{}/expiry/{}/invalid", ARGS.public_path_as_str(), slug)
+            };
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", location))
+                .finish());
+        }
+    }
+
+    // save pasta in database
+    update(Some(&pastas), Some(&pastas[i]));
+
+    let location = if linked {
+        manage::url(&slug, &manage_token)
+    } else if pastas[i].encrypt_server {
+        format!("{}/auth/{}/success", ARGS.public_path_as_str(), slug)
+    } else {
+        format!("{}/upload/{}", ARGS.public_path_as_str(), slug)
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
+
+#[actix_web::test]
+async fn test_anonymous_expiry_change_is_refused() {
+    use actix_web::{test, App};
+    use std::sync::Mutex;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let pasta: Pasta = serde_json::from_value(serde_json::json!({
+        "id": 7, "content": "hello", "file": null, "extension": "txt",
+        "private": false, "readonly": false, "editable": true,
+        "encrypt_server": false, "encrypt_client": false, "encrypted_key": null,
+        "created": now, "expiration": 0, "last_read": now, "read_count": 0,
+        "burn_after_reads": 0, "pasta_type": "text",
+    }))
+    .unwrap();
+    let slug = pasta.id_as_animals();
+    let data = web::Data::new(AppState {
+        pastas: Mutex::new(vec![pasta]),
+        attempts: Default::default(),
+        sessions: Default::default(),
+        two_factor: crate::util::two_factor::TwoFactor::load(),
+        users: crate::util::users::UserStore::load(),
+        oidc: Default::default(),
+    });
+    let app = test::init_service(App::new().app_data(data.clone()).service(post_expiry)).await;
+
+    let body = "--x\r\nContent-Disposition: form-data; name=\"expiration\"\r\n\r\n1min\r\n--x--\r\n";
+    let req = test::TestRequest::post()
+        .uri(&format!("/expiry/{}", slug))
+        .insert_header(("Content-Type", "multipart/form-data; boundary=x"))
+        .set_payload(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+
+    assert_eq!(res.status(), 302);
+    assert!(res.headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .ends_with(&format!("/expiry/{}/forbidden", slug)));
+    assert_eq!(data.pastas.lock().unwrap()[0].expiration, 0);
+}
@@ -2,31 +2,123 @@ use std::fs::File;
 use std::path::PathBuf;
 
 use crate::args::ARGS;
+use crate::endpoints::archive::archive_response;
+use crate::util::access;
 use crate::util::auth;
 use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::files_origin::{self, FileGrant};
 use crate::util::misc::remove_expired;
+use crate::util::security_headers::UPLOAD_CSP;
+use crate::util::attempts;
+use crate::util::crypto::{CryptoError, StreamDecryptor};
 use crate::util::{animalnumbers::to_u64, misc::decrypt_file};
 use crate::AppState;
 use actix_multipart::Multipart;
+use actix_web::error::{
+    ErrorForbidden, ErrorInternalServerError, ErrorTooManyRequests, ErrorUnauthorized,
+};
 use actix_web::http::header;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::stream;
 
 use std::collections::HashMap;
 
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Supports a single range of the forms "bytes=a-b", "bytes=a-" and "bytes=-n",
+// anything else is answered with the whole file.
+fn parse_range(header: Option<&header::HeaderValue>, len: u64) -> ByteRange {
+    let spec = match header
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => Some((start, end.min(len.saturating_sub(1)))),
+        (Ok(start), Err(_)) if end.is_empty() => Some((start, len.saturating_sub(1))),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            Some((len.saturating_sub(suffix), len.saturating_sub(1)))
+        }
+        _ => return ByteRange::Full,
+    };
+
+    match range {
+        Some((start, end)) if start < len && start <= end => ByteRange::Partial(start, end),
+        _ => ByteRange::Unsatisfiable,
+    }
+}
+
+fn range_response(header: Option<&header::HeaderValue>, len: u64) -> HttpResponseBuilder {
+    let mut response = match parse_range(header, len) {
+        ByteRange::Full => HttpResponse::Ok(),
+        ByteRange::Partial(start, end) => {
+            let mut response = HttpResponse::PartialContent();
+            response.append_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            ));
+            response
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = HttpResponse::RangeNotSatisfiable();
+            response.append_header((header::CONTENT_RANGE, format!("bytes */{}", len)));
+            response
+        }
+    };
+    response.append_header((header::ACCEPT_RANGES, "bytes"));
+    if !ARGS.disable_security_headers {
+        response.append_header((header::CONTENT_SECURITY_POLICY, UPLOAD_CSP));
+    }
+    response
+}
+
 #[post("/secure_file/{id}")]
 pub async fn post_secure_file(
+    request: HttpRequest,
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
     query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let password = auth::password_from_multipart(payload).await?;
+
+    if let Some(denied) = access::denied_for(&data, &request, &id) {
+        return Ok(denied);
+    }
+
+    if files_origin::should_redirect(&request) {
+        return Ok(FileGrant::file(&id, query.get("fname"), false, Some(password)).redirect());
+    }
+
+    secure_file_response(request, data, &id, query.get("fname"), password).await
+}
+
+pub async fn secure_file_response(
+    request: HttpRequest,
+    data: web::Data<AppState>,
+    id: &str,
+    fname: Option<&String>,
+    password: String,
 ) -> Result<HttpResponse, Error> {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
     let id = if ARGS.hash_ids {
-        hashid_to_u64(&id).unwrap_or(0)
+        hashid_to_u64(id).unwrap_or(0)
     } else {
-        to_u64(&id.into_inner()).unwrap_or(0)
+        to_u64(id).unwrap_or(0)
     };
 
     // remove expired pastas (including this one if needed)
@@ -43,11 +135,9 @@ pub async fn post_secure_file(
         }
     }
 
-    let password = auth::password_from_multipart(payload).await?;
-
     if found {
         let mut target_filename = None;
-        if let Some(fname) = query.get("fname") {
+        if let Some(fname) = fname {
              // sanitize fname? It should match one of the attachments or file.
              // Security check: ensure fname is in the list of files for this pasta
              if let Some(file) = &pastas[index].file {
@@ -68,13 +158,13 @@ pub async fn post_secure_file(
         }
 
         // Fallback to primary file if no fname or not found (and fname wasn't provided)
-        if target_filename.is_none() && query.get("fname").is_none() {
+        if target_filename.is_none() && fname.is_none() {
              if let Some(file) = &pastas[index].file {
                  target_filename = Some(file.name());
              }
         }
 
-        if let Some(filename) = target_filename {
+        if let Some(filename) = target_filename.map(str::to_string) {
             // Try new naming scheme {filename}.enc first, then fallback to data.enc (legacy/primary)
             let mut enc_path = format!(
                 "{}/attachments/{}/{}.enc",
@@ -92,26 +182,103 @@ pub async fn post_secure_file(
                 );
             }
 
-            if let Ok(file) = File::open(&enc_path) {
-                // Not compatible with NamedFile from actix_files (it needs a File
-                // to work therefore secure files do not support streaming
-                let decrypted_data: Vec<u8> = decrypt_file(&password, &file)?;
+            let subjects = [
+                attempts::client_subject(&request),
+                attempts::upload_subject(&pastas[index].id_as_animals()),
+            ];
+            if data.attempts.is_locked(&subjects) {
+                return Err(ErrorTooManyRequests(
+                    "Too many incorrect passwords, please try again later.",
+                ));
+            }
+
+            // the file is decrypted and sent without holding the lock
+            drop(pastas);
 
+            if let Ok(mut file) = File::open(&enc_path) {
                 // Set the content type based on the file extension
                 let content_type = mime_guess::from_path(&filename)
                     .first_or_octet_stream()
                     .to_string();
+                let disposition = format!("attachment; filename=\"{}\"", filename);
+                let range = request.headers().get(header::RANGE).cloned();
 
-                // Create a response with the decrypted data
-                let response = HttpResponse::Ok()
+                let decryptor = web::block(move || {
+                    StreamDecryptor::new(&mut file, &password).map(|d| (d, file, password))
+                })
+                .await?
+                .map_err(|e| {
+                    if matches!(e, CryptoError::Decryption) {
+                        data.attempts.record_failure(&subjects);
+                    }
+                    ErrorUnauthorized(e)
+                })?;
+
+                let (decryptor, file, password) = decryptor;
+                let decryptor = match decryptor {
+                    Some(decryptor) => decryptor,
+                    None => {
+                        // files from older versions are sealed in one piece, so they
+                        // still have to be decrypted in memory
+                        let decrypted_data: Vec<u8> = match decrypt_file(&password, &file) {
+                            Ok(decrypted_data) => decrypted_data,
+                            Err(e) => {
+                                data.attempts.record_failure(&subjects);
+                                return Err(ErrorUnauthorized(e.to_string()));
+                            }
+                        };
+                        data.attempts.record_success(&subjects[1]);
+                        let len = decrypted_data.len() as u64;
+                        let mut response = range_response(range.as_ref(), len);
+                        return Ok(match parse_range(range.as_ref(), len) {
+                            ByteRange::Full => response
+                                .content_type(content_type)
+                                .append_header(("Content-Disposition", disposition))
+                                .body(decrypted_data),
+                            ByteRange::Partial(start, end) => response
+                                .content_type(content_type)
+                                .append_header(("Content-Disposition", disposition))
+                                .body(decrypted_data[start as usize..=end as usize].to_vec()),
+                            ByteRange::Unsatisfiable => response.finish(),
+                        });
+                    }
+                };
+
+                data.attempts.record_success(&subjects[1]);
+
+                let len = decryptor.plaintext_len();
+                let (start, end) = match parse_range(range.as_ref(), len) {
+                    ByteRange::Full => (0, len),
+                    ByteRange::Partial(start, end) => (start, end + 1),
+                    ByteRange::Unsatisfiable => {
+                        return Ok(range_response(range.as_ref(), len).finish())
+                    }
+                };
+
+                // decrypt one chunk at a time while the response is being sent
+                let body = stream::try_unfold(
+                    (file, decryptor, start),
+                    move |(mut file, decryptor, position)| async move {
+                        if position >= end {
+                            return Ok(None);
+                        }
+                        let (chunk, file, decryptor) = web::block(move || {
+                            decryptor
+                                .read_at(&mut file, position, end)
+                                .map(|chunk| (chunk, file, decryptor))
+                        })
+                        .await?
+                        .map_err(ErrorInternalServerError)?;
+                        let next = position + chunk.len() as u64;
+                        Ok::<_, Error>(Some((Bytes::from(chunk), (file, decryptor, next))))
+                    },
+                );
+
+                return Ok(range_response(range.as_ref(), len)
                     .content_type(content_type)
-                    .append_header((
-                        "Content-Disposition",
-                        format!("attachment; filename=\"{}\"", filename),
-                    ))
-                    // TODO: make streaming <21-10-24, dvdsk>
-                    .body(decrypted_data);
-                return Ok(response);
+                    .append_header(("Content-Disposition", disposition))
+                    .no_chunking(end - start)
+                    .streaming(body));
             }
         }
     }
@@ -124,14 +291,34 @@ pub async fn get_file(
     id: web::Path<String>,
     data: web::Data<AppState>,
     query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, Error> {
+    let preview = query.get("preview").map(|s| s == "true").unwrap_or(false);
+
+    if let Some(denied) = access::denied_for(&data, &request, &id) {
+        return Ok(denied);
+    }
+
+    if files_origin::should_redirect(&request) {
+        return Ok(FileGrant::file(&id, query.get("fname"), preview, None).redirect());
+    }
+
+    file_response(&request, &data, &id, query.get("fname"), preview)
+}
+
+pub fn file_response(
+    request: &HttpRequest,
+    data: &AppState,
+    id: &str,
+    fname: Option<&String>,
+    preview: bool,
 ) -> Result<HttpResponse, Error> {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
     let id_intern = if ARGS.hash_ids {
-        hashid_to_u64(&id).unwrap_or(0)
+        hashid_to_u64(id).unwrap_or(0)
     } else {
-        to_u64(&id.into_inner()).unwrap_or(0)
+        to_u64(id).unwrap_or(0)
     };
 
     // remove expired pastas (including this one if needed)
@@ -151,7 +338,7 @@ pub async fn get_file(
     if found {
         // Determine which file to serve
         let mut target_file = None;
-        if let Some(fname) = query.get("fname") {
+        if let Some(fname) = fname {
             if let Some(file) = &pastas[index].file {
                 if file.name() == *fname {
                     target_file = Some(file);
@@ -197,7 +384,12 @@ pub async fn get_file(
             // file path
             let file_reponse = actix_files::NamedFile::open(file_path)?;
             
-            let disposition = if query.get("preview").map(|s| s == "true").unwrap_or(false) {
+            // active content is only shown inline where it cannot reach MicroBin's cookies
+            let content_type = file_reponse.content_type().to_string();
+            let disposition = if preview
+                && (!files_origin::is_active_content(&content_type)
+                    || files_origin::is_files_origin(request))
+            {
                 header::DispositionType::Inline
             } else {
                 header::DispositionType::Attachment
@@ -211,9 +403,47 @@ pub async fn get_file(
             });
             // This takes care of streaming/seeking using the Range
             // header in the request.
-            return Ok(file_reponse.into_response(&request));
+            let mut response = file_reponse.into_response(request);
+            if !ARGS.disable_security_headers {
+                response.headers_mut().insert(
+                    header::CONTENT_SECURITY_POLICY,
+                    header::HeaderValue::from_static(UPLOAD_CSP),
+                );
+            }
+            return Ok(response);
         }
     }
 
     Ok(HttpResponse::NotFound().finish())
 }
+
+#[get("/f/{token}")]
+pub async fn get_file_with_token(
+    request: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let grant = match FileGrant::open(&token) {
+        Some(grant) => grant,
+        None => {
+            return Err(ErrorForbidden(
+                "This download link has expired. Please go back and try again.",
+            ))
+        }
+    };
+
+    let mut response = if grant.archive {
+        archive_response(&data, &grant.id)?
+    } else if let Some(password) = grant.password {
+        secure_file_response(request, data, &grant.id, grant.fname.as_ref(), password).await?
+    } else {
+        file_response(&request, &data, &grant.id, grant.fname.as_ref(), grant.preview)?
+    };
+
+    // pages of the main origin fetch files encrypted in the browser from here
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        header::HeaderValue::from_static("*"),
+    );
+    Ok(response)
+}
@@ -1,9 +1,10 @@
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use askama::Template;
 
 use crate::args::{Args, ARGS};
 use crate::pasta::Pasta;
 use crate::util::misc::remove_expired;
+use crate::util::security_headers;
 use crate::AppState;
 
 #[derive(Template)]
@@ -11,10 +12,11 @@ use crate::AppState;
 struct ListTemplate<'a> {
     pastas: &'a Vec<Pasta>,
     args: &'a Args,
+    nonce: String,
 }
 
 #[get("/list")]
-pub async fn list(data: web::Data<AppState>) -> HttpResponse {
+pub async fn list(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
     if ARGS.no_listing {
         return HttpResponse::Found()
             .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
@@ -32,6 +34,7 @@ pub async fn list(data: web::Data<AppState>) -> HttpResponse {
         ListTemplate {
             pastas: &pastas,
             args: &ARGS,
+            nonce: security_headers::nonce(&req),
         }
         .render()
         .unwrap(),
@@ -0,0 +1,164 @@
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::endpoints::{edit, expiry, privacy, remove, share};
+use crate::pasta::Pasta;
+use crate::util::animalnumbers::to_u64;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::manage;
+use crate::util::misc::remove_expired;
+use crate::AppState;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "manage.html")]
+struct ManageTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    manage_url: String,
+}
+
+// unknown uploads and wrong tokens look the same
+fn not_found() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+}
+
+fn token_is_valid(data: &AppState, id: &str, token: &str) -> bool {
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    };
+
+    data.pastas
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|pasta| pasta.id == id && manage::token_matches(pasta, token))
+}
+
+#[get("/manage/{id}/{token}")]
+pub async fn get_manage(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    let mut pastas = data.pastas.lock().unwrap();
+
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id).unwrap_or(0)
+    };
+
+    remove_expired(&mut pastas);
+
+    match pastas
+        .iter()
+        .find(|pasta| pasta.id == id && manage::token_matches(pasta, &token))
+    {
+        Some(pasta) => HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+            ManageTemplate {
+                args: &ARGS,
+                pasta,
+                manage_url: manage::url(&pasta.id_as_animals(), &token),
+            }
+            .render()
+            .unwrap(),
+        ),
+        None => not_found(),
+    }
+}
+
+#[get("/manage/{id}/{token}/edit")]
+pub async fn get_manage_edit(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    edit::edit_page(&data, &req, &id, &token)
+}
+
+#[get("/manage/{id}/{token}/expiry")]
+pub async fn get_manage_expiry(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    expiry::expiry_page(&data, &req, &id, String::from(""), &token)
+}
+
+#[get("/manage/{id}/{token}/expiry/{status}")]
+pub async fn get_manage_expiry_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token, status) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    expiry::expiry_page(&data, &req, &id, status, &token)
+}
+
+#[get("/manage/{id}/{token}/remove")]
+pub async fn get_manage_remove(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    remove::remove_page(&data, &req, &id, &token)
+}
+
+#[get("/manage/{id}/{token}/shares")]
+pub async fn get_manage_shares(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    share::shares_page(&data, &req, &id, &token)
+}
+
+#[get("/manage/{id}/{token}/privacy")]
+pub async fn get_manage_privacy(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    privacy::privacy_page(&data, &req, &id, String::from(""), &token)
+}
+
+#[get("/manage/{id}/{token}/privacy/{status}")]
+pub async fn get_manage_privacy_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, token, status) = param.into_inner();
+    if !token_is_valid(&data, &id, &token) {
+        return not_found();
+    }
+    privacy::privacy_page(&data, &req, &id, status, &token)
+}
@@ -0,0 +1,47 @@
+use crate::args::{Args, ARGS};
+use crate::pasta::Pasta;
+use crate::util::misc::remove_expired;
+use crate::util::recent;
+use crate::AppState;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "mine.html")]
+struct MineTemplate<'a> {
+    args: &'a Args,
+    pastas: &'a Vec<&'a Pasta>,
+}
+
+/// The uploads this browser created, newest first.
+#[get("/mine")]
+pub async fn mine(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let entries = recent::entries(&req);
+    let mine: Vec<&Pasta> = entries
+        .iter()
+        .filter_map(|entry| pastas.iter().find(|pasta| pasta.id == entry.id))
+        .collect();
+
+    let mut response = HttpResponse::Ok();
+    // forget uploads that have expired or were removed
+    if mine.len() < entries.len() {
+        let kept: Vec<recent::Recent> = entries
+            .into_iter()
+            .filter(|entry| mine.iter().any(|pasta| pasta.id == entry.id))
+            .collect();
+        response.cookie(recent::cookie(&kept));
+    }
+
+    response.content_type("text/html; charset=utf-8").body(
+        MineTemplate {
+            args: &ARGS,
+            pastas: &mine,
+        }
+        .render()
+        .unwrap(),
+    )
+}
@@ -0,0 +1,100 @@
+use crate::args::ARGS;
+use crate::util::oidc::{self, safe_next, state_removal_cookie};
+use crate::util::session::Principal;
+use crate::AppState;
+use actix_web::error::{ErrorBadGateway, ErrorForbidden};
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[get("/oidc/login")]
+pub async fn get_oidc_login(
+    data: web::Data<AppState>,
+    query: web::Query<LoginQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !oidc::is_enabled() {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", format!("{}/", ARGS.public_path_as_str())))
+            .finish());
+    }
+
+    let next = safe_next(query.next.as_deref());
+    let (url, cookie) = data.oidc.start(&req, next).await.map_err(|e| {
+        log::error!("{}", e);
+        ErrorBadGateway("Single sign-on is not available right now. Please try again later.")
+    })?;
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", url))
+        .cookie(cookie)
+        .finish())
+}
+
+#[get("/oidc/callback")]
+pub async fn get_oidc_callback(
+    data: web::Data<AppState>,
+    query: web::Query<CallbackQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if let Some(error) = &query.error {
+        log::warn!(
+            "The identity provider ended a sign in with {}: {}",
+            error,
+            query.error_description.as_deref().unwrap_or_default()
+        );
+        return Err(ErrorForbidden("The identity provider did not sign you in."));
+    }
+
+    // without single sign-on no sign in was started, so finishing one fails below
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(ErrorForbidden("Invalid sign in.")),
+    };
+
+    let (claims, next) = data.oidc.finish(&req, code, state).await.map_err(|e| {
+        log::warn!("{}", e);
+        ErrorForbidden("The sign in failed. Please try again.")
+    })?;
+
+    let (subject, name) = oidc::identity(&claims);
+    let principal = if oidc::is_admin(&claims) {
+        log::info!("{} signed in as admin through single sign-on.", name);
+        Principal::Admin
+    } else {
+        let user = data.users.find_or_create_sso(&subject, &name);
+        log::info!("User {} signed in through single sign-on.", user.username);
+        Principal::User(user.id)
+    };
+
+    // the session cookie is strictly same site, so a browser arriving from the identity
+    // provider would not send it along on a redirect; a page of our own moves on instead
+    let location = html_escape::encode_double_quoted_attribute(&format!(
+        "{}{}",
+        ARGS.public_path_as_str(),
+        next
+    ))
+    .to_string();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .cookie(data.sessions.create(principal))
+        .cookie(state_removal_cookie())
+        .body(format!(
+            "<!DOCTYPE html><html><head><meta http-equiv=\"refresh\" content=\"0;url={0}\"></head>\
+             <body><a href=\"{0}\">Continue</a></body></html>",
+            location
+        )))
+}
@@ -1,16 +1,21 @@
 use crate::args::{Args, ARGS};
 use crate::endpoints::errors::ErrorTemplate;
 use crate::pasta::Pasta;
+use crate::util::access;
 use crate::util::animalnumbers::to_u64;
+use crate::util::attempts;
 use crate::util::auth;
 use crate::util::db::update;
 use crate::util::hashids::to_u64 as hashid_to_u64;
-use crate::util::misc::remove_expired;
+use crate::util::manage;
+use crate::util::misc::{decrypt, remove_expired, unlocked_copy};
+use crate::util::recent;
+use crate::util::security_headers;
+use crate::util::share;
 use crate::AppState;
 use actix_multipart::Multipart;
 use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use askama::Template;
-use magic_crypt::{new_magic_crypt, MagicCryptTrait};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -19,13 +24,21 @@ use std::time::{SystemTime, UNIX_EPOCH};
 struct PastaTemplate<'a> {
     pasta: &'a Pasta,
     args: &'a Args,
+    // shown once to the uploader right after creating the upload
+    manage_url: Option<String>,
+    // viewed through a share link, which only gives access to the content and files
+    shared: bool,
+    file_url: String,
+    nonce: String,
 }
 
-fn pastaresponse(
+pub fn pastaresponse(
     data: web::Data<AppState>,
     id: web::Path<String>,
     password: String,
     skip_increment: bool,
+    req: &HttpRequest,
+    share_token: Option<&str>,
 ) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -51,6 +64,10 @@ fn pastaresponse(
     }
 
     if found {
+        if let Err(denied) = access::check(&data, req, &pastas[index]) {
+            return access::denied_response(req, denied);
+        }
+
         if pastas[index].encrypt_server && password == *"" {
             return HttpResponse::Found()
                 .append_header((
@@ -60,6 +77,20 @@ fn pastaresponse(
                 .finish();
         }
 
+        let subjects = [
+            attempts::client_subject(req),
+            attempts::upload_subject(&pastas[index].id_as_animals()),
+        ];
+        // the password of a share link is known to be right
+        if password != *"" && share_token.is_none() && data.attempts.is_locked(&subjects) {
+            return HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/auth/{}/locked", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                ))
+                .finish();
+        }
+
         if !skip_increment {
             // increment read count
             pastas[index].read_count += 1;
@@ -68,39 +99,57 @@ fn pastaresponse(
             update(Some(&pastas), Some(&pastas[index]));
         }
 
-        let original_content = pastas[index].content.to_owned();
+        let mut pasta = unlocked_copy(pastas, index);
 
         // decrypt content temporarily
-        if password != *"" && !original_content.is_empty() {
-            let res = decrypt(&original_content, &password);
-            if let Ok(..) = res {
-                pastas[index]
-                    .content
-                    .replace_range(.., res.unwrap().as_str());
-            } else {
-                return HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-                    ))
-                    .finish();
+        if password != *"" && !pasta.content.is_empty() {
+            match decrypt(&pasta.content, &password) {
+                Ok(content) => {
+                    data.attempts.record_success(&subjects[1]);
+                    pasta.content = content;
+                }
+                Err(_) => {
+                    data.attempts.record_failure(&subjects);
+                    return HttpResponse::Found()
+                        .append_header((
+                            "Location",
+                            format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
+                        ))
+                        .finish();
+                }
             }
         }
 
+        let manage_url = match share_token {
+            Some(_) => None,
+            None => manage::flashed_url(req, &pasta),
+        };
+        let mut response = HttpResponse::Ok();
+        if manage_url.is_some() {
+            response.cookie(manage::flash_removal_cookie());
+        }
+
         // serve pasta in template
-        let response = HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        let response = response.content_type("text/html; charset=utf-8").body(
             PastaTemplate {
-                pasta: &pastas[index],
+                pasta: &pasta,
                 args: &ARGS,
+                manage_url,
+                shared: share_token.is_some(),
+                file_url: match share_token {
+                    Some(token) => format!("{}/file", share::url(token)),
+                    None => format!(
+                        "{}/file/{}",
+                        ARGS.public_path_as_str(),
+                        pasta.id_as_animals()
+                    ),
+                },
+                nonce: security_headers::nonce(req),
             }
             .render()
             .unwrap(),
         );
 
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
-        }
-
         // get current unix time in seconds
         let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
             Ok(n) => n.as_secs(),
@@ -110,11 +159,14 @@ fn pastaresponse(
             }
         } as i64;
 
-        // update last read time
-        pastas[index].last_read = timenow;
+        // update last read time, unless the upload went away in the meantime
+        let mut pastas = data.pastas.lock().unwrap();
+        if let Some(index) = pastas.iter().position(|pasta| pasta.id == id) {
+            pastas[index].last_read = timenow;
 
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+            // save the updated read count
+            update(Some(&pastas), Some(&pastas[index]));
+        }
 
         return response;
     }
@@ -130,9 +182,10 @@ pub async fn postpasta(
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
-    Ok(pastaresponse(data, id, password, false))
+    Ok(pastaresponse(data, id, password, false, &req, None))
 }
 
 #[post("/p/{id}")]
@@ -140,9 +193,10 @@ pub async fn postshortpasta(
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
-    Ok(pastaresponse(data, id, password, false))
+    Ok(pastaresponse(data, id, password, false, &req, None))
 }
 
 #[get("/upload/{id}")]
@@ -151,53 +205,29 @@ pub async fn getpasta(
     id: web::Path<String>,
     req: HttpRequest,
 ) -> HttpResponse {
-    let mut skip_increment = false;
-
-    // the user attached an owner_token. likely they're the same user that created the pasta
-    // but let's verify it just in case
-    if let Some(cookie) = req.cookie("owner_token") {
-        if verify_owner_token(cookie.value(), &id) {
-            // yay, it really is the same user and their cookie isn't expired
-            // so let's skip incrementing the read count
-            skip_increment = true;
-        }
-    }
+    let intern_id = if ARGS.hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id).unwrap_or(0)
+    };
 
-    pastaresponse(data, id, String::from(""), skip_increment)
-}
+    // the uploader is sent here right after creating the upload, which is not a read
+    let skip_increment = recent::is_fresh_upload(&req, intern_id);
 
-// when creating a pasta, the owner is issued a token with a 15-second expiration
-// this token is used to avoid incrementing the read count of the pasta when the owner views it
-fn verify_owner_token(token: &str, id: &str) -> bool {
-    // decode the token
-    if let Ok(numbers) = crate::util::hashids::HARSH.decode(token) {
-        if numbers.len() == 2 {
-            let expiry = numbers[0];
-            let token_id = numbers[1];
-            let timenow = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
-            // verify the token is valid
-            let target_id = if ARGS.hash_ids {
-                hashid_to_u64(id).unwrap_or(0)
-            } else {
-                to_u64(id).unwrap_or(0)
-            };
-
-            if token_id == target_id && expiry > timenow {
-                // yay, it's valid
-                return true;
-            }
-        }
-    }
-    false
+    pastaresponse(data, id, String::from(""), skip_increment, &req, None)
 }
 
+
 #[get("/p/{id}")]
-pub async fn getshortpasta(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    pastaresponse(data, id, String::from(""), false)
+pub async fn getshortpasta(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    pastaresponse(data, id, String::from(""), false, &req, None)
 }
 
-fn urlresponse(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+fn urlresponse(data: web::Data<AppState>, id: web::Path<String>, req: HttpRequest) -> HttpResponse {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
 
@@ -223,6 +253,10 @@ fn urlresponse(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse
     }
 
     if found {
+        if let Err(denied) = access::check(&data, &req, &pastas[index]) {
+            return access::denied_response(&req, denied);
+        }
+
         // increment read count
         pastas[index].read_count += 1;
 
@@ -266,19 +300,28 @@ fn urlresponse(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse
 }
 
 #[get("/url/{id}")]
-pub async fn redirecturl(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    urlresponse(data, id)
+pub async fn redirecturl(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    urlresponse(data, id, req)
 }
 
 #[get("/u/{id}")]
-pub async fn shortredirecturl(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
-    urlresponse(data, id)
+pub async fn shortredirecturl(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    urlresponse(data, id, req)
 }
 
 #[get("/raw/{id}")]
 pub async fn getrawpasta(
     data: web::Data<AppState>,
     id: web::Path<String>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     // get access to the pasta collection
     let mut pastas = data.pastas.lock().unwrap();
@@ -304,6 +347,10 @@ pub async fn getrawpasta(
     }
 
     if found {
+        if let Err(denied) = access::check(&data, &req, &pastas[index]) {
+            return Ok(access::denied_response(&req, denied));
+        }
+
         if pastas[index].encrypt_server {
             return Ok(HttpResponse::Found()
                 .append_header((
@@ -350,6 +397,7 @@ pub async fn postrawpasta(
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let password = auth::password_from_multipart(payload).await?;
 
@@ -377,6 +425,10 @@ pub async fn postrawpasta(
     }
 
     if found {
+        if let Err(denied) = access::check(&data, &req, &pastas[index]) {
+            return Ok(access::denied_response(&req, denied));
+        }
+
         if pastas[index].encrypt_server && password == *"" {
             return Ok(HttpResponse::Found()
                 .append_header((
@@ -386,28 +438,44 @@ pub async fn postrawpasta(
                 .finish());
         }
 
+        let subjects = [
+            attempts::client_subject(&req),
+            attempts::upload_subject(&pastas[index].id_as_animals()),
+        ];
+        if password != *"" && data.attempts.is_locked(&subjects) {
+            return Ok(HttpResponse::Found()
+                .append_header((
+                    "Location",
+                    format!("{}/auth/{}/locked", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
+                ))
+                .finish());
+        }
+
         // increment read count
         pastas[index].read_count += 1;
 
         // save the updated read count
         update(Some(&pastas), Some(&pastas[index]));
 
-        let original_content = pastas[index].content.to_owned();
+        let pasta = unlocked_copy(pastas, index);
+        let slug = pasta.id_as_animals();
+        let mut content = pasta.content;
 
-        // decrypt content temporarily
         if password != *"" {
-            let res = decrypt(&original_content, &password);
-            if res.is_ok() {
-                pastas[index]
-                    .content
-                    .replace_range(.., res.unwrap().as_str());
-            } else {
-                return Ok(HttpResponse::Found()
-                    .append_header((
-                        "Location",
-                        format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), pastas[index].id_as_animals()),
-                    ))
-                    .finish());
+            match decrypt(&content, &password) {
+                Ok(decrypted) => {
+                    data.attempts.record_success(&subjects[1]);
+                    content = decrypted;
+                }
+                Err(_) => {
+                    data.attempts.record_failure(&subjects);
+                    return Ok(HttpResponse::Found()
+                        .append_header((
+                            "Location",
+                            format!("{}/auth/{}/incorrect", ARGS.public_path_as_str(), slug),
+                        ))
+                        .finish());
+                }
             }
         }
 
@@ -420,20 +488,19 @@ pub async fn postrawpasta(
             }
         } as i64;
 
-        // update last read time
-        pastas[index].last_read = timenow;
+        // update last read time, unless the upload went away in the meantime
+        let mut pastas = data.pastas.lock().unwrap();
+        if let Some(index) = pastas.iter().position(|pasta| pasta.id == id) {
+            pastas[index].last_read = timenow;
 
-        // save the updated read count
-        update(Some(&pastas), Some(&pastas[index]));
+            // save the updated read count
+            update(Some(&pastas), Some(&pastas[index]));
+        }
 
         // send raw content of pasta
         let response = Ok(HttpResponse::Ok()
             .content_type("text/plain; charset=utf-8")
-            .body(pastas[index].content.to_owned()));
-
-        if pastas[index].content != original_content {
-            pastas[index].content = original_content;
-        }
+            .body(content));
 
         return response;
     }
@@ -443,9 +510,3 @@ pub async fn postrawpasta(
         .content_type("text/html; charset=utf-8")
         .body(String::from("Upload not found! :-(")))
 }
-
-fn decrypt(text_str: &str, key_str: &str) -> Result<String, magic_crypt::MagicCryptError> {
-    let mc = new_magic_crypt!(key_str, 256);
-
-    mc.decrypt_base64_to_string(text_str)
-}
@@ -0,0 +1,388 @@
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::util::animalnumbers::to_u64;
+use crate::util::attempts;
+use crate::util::auth::{self, read_fields};
+use crate::util::crypto::{self, CryptoError, SealWriter};
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::db::update;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::manage;
+use crate::util::misc::{decrypt, decrypt_file_into, encrypt, remove_expired, unlocked_copy};
+use crate::{AppState, Pasta};
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Privacy {
+    Public,
+    Unlisted,
+    Readonly,
+    Private,
+}
+
+impl Privacy {
+    fn parse(value: &str) -> Option<Privacy> {
+        match value {
+            "public" => Some(Privacy::Public),
+            "unlisted" if ARGS.private => Some(Privacy::Unlisted),
+            "readonly" if ARGS.enable_readonly => Some(Privacy::Readonly),
+            "private" if ARGS.encryption_server_side => Some(Privacy::Private),
+            _ => None,
+        }
+    }
+
+    // uploads encrypted in the browser have no level the server could change
+    fn of(pasta: &Pasta) -> Option<Privacy> {
+        if pasta.encrypt_client || pasta.encrypt_fragment {
+            None
+        } else if pasta.encrypt_server {
+            Some(Privacy::Private)
+        } else if pasta.readonly {
+            Some(Privacy::Readonly)
+        } else if pasta.private {
+            Some(Privacy::Unlisted)
+        } else {
+            Some(Privacy::Public)
+        }
+    }
+
+    fn needs_password(self) -> bool {
+        matches!(self, Privacy::Readonly | Privacy::Private)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "privacy.html")]
+struct PrivacyTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    status: String,
+    // false for uploads without a password, which only their uploader can change
+    allowed: bool,
+    needs_password: bool,
+    manage_token: &'a str,
+    csrf_token: String,
+}
+
+/// The page that changes the privacy or password of an upload, for the management link
+/// of the upload too.
+pub fn privacy_page(
+    data: &AppState,
+    req: &HttpRequest,
+    id: &str,
+    status: String,
+    manage_token: &str,
+) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    };
+
+    remove_expired(&mut pastas);
+
+    for pasta in pastas.iter() {
+        if pasta.id == id && Privacy::of(pasta).is_some() {
+            let managed = data.sessions.can_manage(req, pasta) || manage::token_matches(pasta, manage_token);
+            return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+                PrivacyTemplate {
+                    args: &ARGS,
+                    pasta,
+                    status,
+                    allowed: managed || auth::pasta_needs_password(pasta),
+                    // the content of private uploads cannot be read without it
+                    needs_password: pasta.encrypt_server
+                        || (auth::pasta_needs_password(pasta) && !managed),
+                    manage_token,
+                    csrf_token: csrf::token(req),
+                }
+                .render()
+                .unwrap(),
+            );
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+}
+
+// Writes a file as the new privacy level stores it, to `staged` next to where it ends
+// up. The file is streamed from one to the other and never written in plaintext when it
+// stays encrypted.
+fn stage_file(
+    source: &str,
+    staged: &str,
+    password: Option<&str>,
+    new_password: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = BufWriter::new(File::create(staged)?);
+    match (password, new_password) {
+        (Some(password), Some(new_password)) => {
+            let mut sealer = SealWriter::new(output, new_password)?;
+            decrypt_file_into(password, source, &mut sealer)?;
+            sealer.finish()?;
+        }
+        (Some(password), None) => decrypt_file_into(password, source, &mut output)?,
+        (None, Some(new_password)) => {
+            crypto::seal_stream(BufReader::new(File::open(source)?), output, new_password)?
+        }
+        (None, None) => {
+            io::copy(&mut File::open(source)?, &mut output)?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// A file written for the new privacy level, moved in place by `commit_files`.
+struct StagedFile {
+    staged: String,
+    path: String,
+    old_path: String,
+}
+
+fn discard_files(staged: &[StagedFile]) {
+    for file in staged {
+        let _ = fs::remove_file(&file.staged);
+    }
+}
+
+fn commit_files(staged: &[StagedFile]) -> std::io::Result<()> {
+    for file in staged {
+        fs::rename(&file.staged, &file.path)?;
+        if file.old_path != file.path {
+            fs::remove_file(&file.old_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Decrypts the content and files of the upload with its current password and prepares
+// them as the new privacy level asks, encrypted with the new password if it asks for
+// one. The files are only staged; nothing replaces the stored ones unless every file
+// could be written, and files that end up encrypted are never written in plaintext.
+fn change_privacy(
+    pasta: &mut Pasta,
+    password: &str,
+    target: Privacy,
+    new_password: &str,
+) -> Result<Vec<StagedFile>, Box<dyn std::error::Error>> {
+    let content = if pasta.encrypt_server {
+        decrypt(&pasta.content, password)?
+    } else {
+        pasta.content.to_owned()
+    };
+
+    let mut staged: Vec<StagedFile> = Vec::new();
+    if pasta.encrypt_server || target == Privacy::Private {
+        let dir = format!("{}/attachments/{}", ARGS.data_dir, pasta.id_as_animals());
+        // concurrent changes of the same upload must not write to each other's files
+        let suffix = format!("{:016x}.privacy", rand::random::<u64>());
+        for file in pasta.all_files() {
+            let path = if target == Privacy::Private {
+                format!("{}/{}.enc", dir, file.name())
+            } else {
+                format!("{}/{}", dir, file.name())
+            };
+            let file = StagedFile {
+                staged: format!("{}.{}", path, suffix),
+                path,
+                old_path: pasta.stored_file_path(file.name()),
+            };
+            let result = stage_file(
+                &file.old_path,
+                &file.staged,
+                Some(password).filter(|_| pasta.encrypt_server),
+                Some(new_password).filter(|_| target == Privacy::Private),
+            );
+            staged.push(file);
+            if let Err(e) = result {
+                discard_files(&staged);
+                return Err(e);
+            }
+        }
+    }
+
+    pasta.content = if target == Privacy::Private {
+        encrypt(&content, new_password)
+    } else {
+        content
+    };
+    pasta.private = target != Privacy::Public;
+    pasta.readonly = target == Privacy::Readonly;
+    pasta.encrypt_server = target == Privacy::Private;
+    pasta.encrypted_key = if target == Privacy::Readonly {
+        Some(encrypt(&pasta.id.to_string(), new_password))
+    } else {
+        Some(String::from(""))
+    };
+
+    Ok(staged)
+}
+
+#[get("/privacy/{id}")]
+pub async fn get_privacy(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    privacy_page(&data, &req, &id, String::from(""), "")
+}
+
+#[get("/privacy/{id}/{status}")]
+pub async fn get_privacy_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (id, status) = param.into_inner();
+    privacy_page(&data, &req, &id, status, "")
+}
+
+#[post("/privacy/{id}")]
+pub async fn post_privacy(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(&id).unwrap_or(0)
+    } else {
+        to_u64(&id.into_inner()).unwrap_or(0)
+    };
+
+    let [privacy, password, new_password, manage_token, csrf_token] = read_fields(
+        payload,
+        ["privacy", "password", "new_password", "manage_token", CSRF_FIELD],
+    )
+    .await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let (linked, managed, original) = {
+        let mut pastas = data.pastas.lock().unwrap();
+        remove_expired(&mut pastas);
+
+        let index = pastas
+            .iter()
+            .position(|pasta| pasta.id == id && Privacy::of(pasta).is_some());
+        match index {
+            Some(index) => {
+                let linked = manage::token_matches(&pastas[index], &manage_token);
+                let managed = linked || data.sessions.can_manage(&req, &pastas[index]);
+                (linked, managed, unlocked_copy(pastas, index))
+            }
+            None => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+            }
+        }
+    };
+
+    let slug = original.id_as_animals();
+    let redirect = |status: &str| {
+        let location = if linked {
+            format!("{}/privacy/{}", manage::url(&slug, &manage_token), status)
+        } else {
+            format!("{}/privacy/{}/{}", ARGS.public_path_as_str(), slug, status)
+        };
+        HttpResponse::Found()
+            .append_header(("Location", location))
+            .finish()
+    };
+
+    // without a password, nothing but the management link shows who uploaded it
+    if !managed && !auth::pasta_needs_password(&original) {
+        return Ok(redirect("forbidden"));
+    }
+
+    let target = match Privacy::parse(&privacy) {
+        Some(target) => target,
+        None => return Ok(redirect("unavailable")),
+    };
+    if target.needs_password() && new_password.trim().is_empty() {
+        return Ok(redirect("no_password"));
+    }
+
+    let subjects = [attempts::client_subject(&req), attempts::upload_subject(&slug)];
+    if original.encrypt_server || !managed {
+        if data.attempts.is_locked(&subjects) {
+            return Ok(redirect("locked"));
+        }
+        if !managed && !auth::pasta_password_matches(&original, &password) {
+            data.attempts.record_failure(&subjects);
+            return Ok(redirect("incorrect"));
+        }
+    }
+
+    let mut pasta = original.clone();
+    let staged = match change_privacy(&mut pasta, &password, target, new_password.trim()) {
+        Ok(staged) => staged,
+        Err(e) => {
+            if matches!(e.downcast_ref::<CryptoError>(), Some(CryptoError::Decryption)) {
+                data.attempts.record_failure(&subjects);
+                return Ok(redirect("incorrect"));
+            }
+            log::error!("Failed to change the privacy of {}: {}", slug, e);
+            return Ok(redirect("failed"));
+        }
+    };
+    data.attempts.record_success(&subjects[1]);
+
+    let mut pastas = data.pastas.lock().unwrap();
+    let index = pastas.iter().position(|current| {
+        current.id == id
+            && current.version == original.version
+            && current.content == original.content
+            && current.encrypted_key == original.encrypted_key
+            && Privacy::of(current) == Privacy::of(&original)
+    });
+    let i = match index {
+        Some(i) => i,
+        None => {
+            discard_files(&staged);
+            log::warn!("{} was changed while its privacy was being changed.", slug);
+            return Ok(redirect("failed"));
+        }
+    };
+
+    if let Err(e) = commit_files(&staged) {
+        discard_files(&staged);
+        log::error!("Failed to change the privacy of {}: {}", slug, e);
+        return Ok(redirect("failed"));
+    }
+
+    pastas[i].content = pasta.content;
+    pastas[i].private = pasta.private;
+    pastas[i].readonly = pasta.readonly;
+    pastas[i].encrypt_server = pasta.encrypt_server;
+    pastas[i].encrypted_key = pasta.encrypted_key;
+
+    // share links carry the old password, or none where one is needed now
+    if original.encrypt_server || target == Privacy::Private {
+        pastas[i].shares.clear();
+    }
+
+    // save pasta in database
+    update(Some(&pastas), Some(&pastas[i]));
+
+    let location = if linked {
+        manage::url(&slug, &manage_token)
+    } else if pastas[i].encrypt_server {
+        format!("{}/auth/{}/success", ARGS.public_path_as_str(), slug)
+    } else {
+        format!("{}/upload/{}", ARGS.public_path_as_str(), slug)
+    };
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish())
+}
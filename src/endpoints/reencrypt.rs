@@ -0,0 +1,232 @@
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::util::animalnumbers::to_u64;
+use crate::util::auth::read_fields;
+use crate::util::crypto::SealWriter;
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::db::update;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::misc::{decrypt, decrypt_file_into, encrypt, remove_expired, unlocked_copy};
+use crate::util::security_headers;
+use crate::{AppState, Pasta};
+use actix_multipart::Multipart;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use std::fs::{self, File};
+use std::io::BufWriter;
+
+#[derive(Template)]
+#[template(path = "reencrypt.html")]
+struct ReencryptTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    status: String,
+    csrf_token: String,
+    nonce: String,
+}
+
+fn reencrypt_page(
+    data: web::Data<AppState>,
+    req: &HttpRequest,
+    id: &str,
+    status: String,
+) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    };
+
+    remove_expired(&mut pastas);
+
+    for pasta in pastas.iter() {
+        if pasta.id == id {
+            return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+                ReencryptTemplate {
+                    args: &ARGS,
+                    pasta,
+                    status,
+                    csrf_token: csrf::token(req),
+                    nonce: security_headers::nonce(req),
+                }
+                .render()
+                .unwrap(),
+            );
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+}
+
+// Decrypts everything the server encrypted for the pasta and encrypts it again in
+// the current format. The files are streamed into staged copies, returned as pairs
+// of staged and stored path; nothing replaces the stored ones unless all parts could
+// be decrypted.
+fn reencrypt(pasta: &mut Pasta, key: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    if pasta.readonly {
+        let encrypted_key = pasta.encrypted_key.as_deref().unwrap_or_default();
+        if decrypt(encrypted_key, key)? != pasta.id.to_string() {
+            return Err("Incorrect password".into());
+        }
+        pasta.encrypted_key = Some(encrypt(&pasta.id.to_string(), key));
+        return Ok(Vec::new());
+    }
+
+    if !pasta.encrypt_server {
+        return Ok(Vec::new());
+    }
+
+    let content = decrypt(&pasta.content, key)?;
+
+    // concurrent runs for the same upload must not write to each other's files
+    let suffix = format!("{:016x}.reencrypt", rand::random::<u64>());
+    let mut staged: Vec<(String, String)> = Vec::new();
+    for file in pasta.all_files() {
+        let path = pasta.stored_file_path(file.name());
+        let staged_path = format!("{}.{}", path, suffix);
+        let result = reseal_file(&path, &staged_path, key);
+        staged.push((staged_path, path));
+        if let Err(e) = result {
+            discard_files(&staged);
+            return Err(e);
+        }
+    }
+    pasta.content = encrypt(&content, key);
+
+    Ok(staged)
+}
+
+// streams the file into `staged`, decrypted and encrypted again in the current format
+fn reseal_file(path: &str, staged: &str, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sealer = SealWriter::new(BufWriter::new(File::create(staged)?), key)?;
+    decrypt_file_into(key, path, &mut sealer)?;
+    sealer.finish()?;
+    Ok(())
+}
+
+fn discard_files(staged: &[(String, String)]) {
+    for (staged_path, _) in staged {
+        let _ = fs::remove_file(staged_path);
+    }
+}
+
+fn redirect_to_login() -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", format!("{}/auth_admin", ARGS.public_path_as_str())))
+        .finish()
+}
+
+#[get("/reencrypt/{id}")]
+pub async fn get_reencrypt(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !data.sessions.is_admin(&req) {
+        return redirect_to_login();
+    }
+    reencrypt_page(data, &req, &id, String::from(""))
+}
+
+#[get("/reencrypt/{id}/{status}")]
+pub async fn get_reencrypt_with_status(
+    data: web::Data<AppState>,
+    param: web::Path<(String, String)>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !data.sessions.is_admin(&req) {
+        return redirect_to_login();
+    }
+    let (id, status) = param.into_inner();
+    reencrypt_page(data, &req, &id, status)
+}
+
+#[post("/reencrypt/{id}")]
+pub async fn post_reencrypt(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if !data.sessions.is_admin(&req) {
+        return Ok(redirect_to_login());
+    }
+
+    let slug = id.into_inner();
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(&slug).unwrap_or(0)
+    } else {
+        to_u64(&slug).unwrap_or(0)
+    };
+
+    let [pasta_password, csrf_token] = read_fields(payload, ["pasta_password", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let redirect = |status: &str| {
+        HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!("{}/reencrypt/{}/{}", ARGS.public_path_as_str(), slug, status),
+            ))
+            .finish()
+    };
+
+    let original = {
+        let mut pastas = data.pastas.lock().unwrap();
+        remove_expired(&mut pastas);
+        let index = pastas.iter().position(|pasta| pasta.id == id);
+        match index {
+            Some(index) => unlocked_copy(pastas, index),
+            None => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("text/html; charset=utf-8")
+                    .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+            }
+        }
+    };
+
+    let mut pasta = original.clone();
+    let staged = match reencrypt(&mut pasta, &pasta_password) {
+        Ok(staged) => staged,
+        Err(e) => {
+            log::warn!("Failed to re-encrypt {}: {}", slug, e);
+            return Ok(redirect("failed"));
+        }
+    };
+
+    let mut pastas = data.pastas.lock().unwrap();
+    let index = pastas.iter().position(|current| {
+        current.id == id
+            && current.version == original.version
+            && current.content == original.content
+            && current.encrypted_key == original.encrypted_key
+            && current.encrypt_server == original.encrypt_server
+    });
+    let i = match index {
+        Some(i) => i,
+        None => {
+            discard_files(&staged);
+            log::warn!("{} was changed while it was being re-encrypted.", slug);
+            return Ok(redirect("failed"));
+        }
+    };
+
+    for (staged_path, path) in &staged {
+        if let Err(e) = fs::rename(staged_path, path) {
+            discard_files(&staged);
+            log::error!("Failed to re-encrypt {}: {}", slug, e);
+            return Ok(redirect("failed"));
+        }
+    }
+    pastas[i].content = pasta.content;
+    pastas[i].encrypted_key = pasta.encrypted_key;
+
+    // save pasta in database
+    update(Some(&pastas), Some(&pastas[i]));
+
+    Ok(redirect("success"))
+}
@@ -1,33 +1,51 @@
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, Error, HttpResponse};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 
-use crate::args::ARGS;
+use crate::args::{Args, ARGS};
 use crate::endpoints::errors::ErrorTemplate;
 
 use crate::util::animalnumbers::to_u64;
-use crate::util::auth;
+use crate::util::attempts;
+use crate::util::auth::{self, read_fields};
+use crate::util::csrf::{self, CSRF_FIELD};
 use crate::util::db::delete;
 use crate::util::hashids::to_u64 as hashid_to_u64;
-use crate::util::misc::{decrypt, remove_expired};
-use crate::AppState;
+use crate::util::manage;
+use crate::util::misc::{remove_expired, unlocked_copy};
+use crate::{AppState, Pasta};
 use askama::Template;
 use std::fs;
 
-#[get("/remove/{id}")]
-pub async fn remove(data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+#[derive(Template)]
+#[template(path = "remove.html")]
+struct RemoveTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    // false for uploads without a password, which only their uploader can remove
+    allowed: bool,
+    manage_token: &'a str,
+    csrf_token: String,
+}
+
+/// The page that confirms the removal, for the management link of the upload too.
+pub fn remove_page(data: &AppState, req: &HttpRequest, id: &str, manage_token: &str) -> HttpResponse {
     let mut pastas = data.pastas.lock().unwrap();
 
     let id = if ARGS.hash_ids {
-        hashid_to_u64(&id).unwrap_or(0)
+        hashid_to_u64(id).unwrap_or(0)
     } else {
-        to_u64(&id.into_inner()).unwrap_or(0)
+        to_u64(id).unwrap_or(0)
     };
 
-    for (i, pasta) in pastas.iter().enumerate() {
+    remove_expired(&mut pastas);
+
+    for pasta in pastas.iter() {
         if pasta.id == id {
+            let managed = data.sessions.can_manage(req, pasta) || manage::token_matches(pasta, manage_token);
+
             // if it's encrypted or read-only, it needs password to be deleted
             // OR if it is not editable (public immutable), it needs admin password to be deleted
-            if pasta.encrypt_server || pasta.readonly || !pasta.editable {
+            if auth::pasta_needs_password(pasta) && !managed {
                 return HttpResponse::Found()
                     .append_header((
                         "Location",
@@ -36,40 +54,41 @@ pub async fn remove(data: web::Data<AppState>, id: web::Path<String>) -> HttpRes
                     .finish();
             }
 
-            // remove the directory and all its contents
-            if fs::remove_dir_all(format!(
-                "{}/attachments/{}/",
-                ARGS.data_dir,
-                pasta.id_as_animals()
-            ))
-            .is_err()
-            {
-                log::error!("Failed to delete directory for {}!", pasta.id_as_animals())
-            }
-
-            // remove it from in-memory pasta list
-            pastas.remove(i);
-
-            delete(Some(&pastas), Some(id));
-
-            return HttpResponse::Found()
-                .append_header(("Location", format!("{}/list", ARGS.public_path_as_str())))
-                .finish();
+            return HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+                RemoveTemplate {
+                    args: &ARGS,
+                    pasta,
+                    allowed: managed || auth::pasta_needs_password(pasta),
+                    manage_token,
+                    csrf_token: csrf::token(req),
+                }
+                .render()
+                .unwrap(),
+            );
         }
     }
 
-    remove_expired(&mut pastas);
-
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(ErrorTemplate { args: &ARGS }.render().unwrap())
 }
 
+// deletion happens on POST only, so a link or an embedded image cannot remove anything
+#[get("/remove/{id}")]
+pub async fn remove(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    remove_page(&data, &req, &id, "")
+}
+
 #[post("/remove/{id}")]
 pub async fn post_remove(
     data: web::Data<AppState>,
     id: web::Path<String>,
     payload: Multipart,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let id = if ARGS.hash_ids {
         hashid_to_u64(&id).unwrap_or(0)
@@ -77,95 +96,81 @@ pub async fn post_remove(
         to_u64(&id.into_inner()).unwrap_or(0)
     };
 
-    let mut pastas = data.pastas.lock().unwrap();
+    let [password, manage_token, csrf_token] =
+        read_fields(payload, ["password", "manage_token", CSRF_FIELD]).await?;
+    csrf::verify(&req, &csrf_token)?;
 
-    remove_expired(&mut pastas);
+    let not_found = || {
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+    };
 
-    let password = auth::password_from_multipart(payload).await?;
+    let mut pastas = data.pastas.lock().unwrap();
 
-    for (i, pasta) in pastas.iter().enumerate() {
-        if pasta.id == id {
-            if pastas[i].readonly || pastas[i].encrypt_server || !pastas[i].editable {
-                if password != *"" {
-                    let mut is_password_correct = false;
-
-                    if password == *ARGS.auth_admin_password {
-                        is_password_correct = true;
-                    }
-
-                    // if it is read-only, the content is not encrypted, but the key is
-                    if !is_password_correct && pastas[i].readonly {
-                        if let Some(ref encrypted_key) = pastas[i].encrypted_key {
-                            let res = decrypt(encrypted_key, &password);
-                            if let Ok(decrypted_key) = res {
-                                if decrypted_key == id.to_string() {
-                                    is_password_correct = true;
-                                }
-                            }
-                        }
-                    } else if !is_password_correct && pastas[i].encrypt_server {
-                        // if it is not read-only, the content is encrypted
-                        let res = decrypt(pastas[i].content.to_owned().as_str(), &password);
-                        if res.is_ok() {
-                            is_password_correct = true;
-                        }
-                    }
-
-                    if is_password_correct {
-                // remove the directory and all its contents
-                if fs::remove_dir_all(format!(
-                    "{}/attachments/{}/",
-                    ARGS.data_dir,
-                    pasta.id_as_animals()
-                ))
-                .is_err()
-                {
-                    log::error!("Failed to delete directory for {}!", pasta.id_as_animals())
-                }
+    remove_expired(&mut pastas);
 
-                        // remove it from in-memory pasta list
-                        pastas.remove(i);
-
-                        delete(Some(&pastas), Some(id));
-
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/list", ARGS.public_path_as_str()),
-                            ))
-                            .finish());
-                    } else {
-                        return Ok(HttpResponse::Found()
-                            .append_header((
-                                "Location",
-                                format!("{}/auth_remove_private/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                            ))
-                            .finish());
-                    }
-                } else {
-                    return Ok(HttpResponse::Found()
-                        .append_header((
-                            "Location",
-                            format!("{}/auth_remove_private/{}/incorrect", ARGS.public_path_as_str(), pasta.id_as_animals()),
-                        ))
-                        .finish());
-                }
-            }
+    let mut i = match pastas.iter().position(|pasta| pasta.id == id) {
+        Some(i) => i,
+        None => return Ok(not_found()),
+    };
+    let slug = pastas[i].id_as_animals();
+
+    // a signed in admin can remove anything without the password of the upload,
+    // a signed in user their own uploads, and so can the holder of the management link
+    let managed = data.sessions.can_manage(&req, &pastas[i]) || manage::token_matches(&pastas[i], &manage_token);
+
+    // without a password, nothing but the management link shows who uploaded it
+    if !managed && !auth::pasta_needs_password(&pastas[i]) {
+        return Ok(HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!("{}/remove/{}", ARGS.public_path_as_str(), slug),
+            ))
+            .finish());
+    }
 
-            return Ok(HttpResponse::Found()
+    if !managed {
+        let redirect = |status: &str| {
+            HttpResponse::Found()
                 .append_header((
                     "Location",
-                    format!(
-                        "{}/upload/{}",
-                        ARGS.public_path_as_str(),
-                        pastas[i].id_as_animals()
-                    ),
+                    format!("{}/auth_remove_private/{}/{}", ARGS.public_path_as_str(), slug, status),
                 ))
-                .finish());
+                .finish()
+        };
+
+        let subjects = [attempts::client_subject(&req), attempts::upload_subject(&slug)];
+        if data.attempts.is_locked(&subjects) {
+            return Ok(redirect("locked"));
+        }
+
+        let original = unlocked_copy(pastas, i);
+        if !auth::pasta_password_matches(&original, &password) {
+            data.attempts.record_failure(&subjects);
+            return Ok(redirect("incorrect"));
         }
+        data.attempts.record_success(&subjects[1]);
+
+        pastas = data.pastas.lock().unwrap();
+        i = match pastas.iter().position(|pasta| pasta.id == id) {
+            Some(i) if auth::same_password(&pastas[i], &original) => i,
+            Some(_) => return Ok(redirect("incorrect")),
+            None => return Ok(not_found()),
+        };
     }
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(ErrorTemplate { args: &ARGS }.render().unwrap()))
+    // remove the directory and all its contents
+    if fs::remove_dir_all(format!("{}/attachments/{}/", ARGS.data_dir, slug)).is_err() {
+        log::error!("Failed to delete directory for {}!", slug)
+    }
+
+    // remove it from in-memory pasta list
+    pastas.remove(i);
+
+    delete(Some(&pastas), Some(id));
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("{}/list", ARGS.public_path_as_str())))
+        .finish())
 }
@@ -0,0 +1,308 @@
+use actix_multipart::Multipart;
+use actix_web::error::ErrorForbidden;
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+use askama::Template;
+use std::collections::HashMap;
+
+use crate::args::{Args, ARGS};
+use crate::endpoints::errors::ErrorTemplate;
+use crate::endpoints::file::{file_response, secure_file_response};
+use crate::endpoints::pasta::pastaresponse;
+use crate::util::access;
+use crate::util::animalnumbers::to_u64;
+use crate::util::attempts;
+use crate::util::auth::{self, read_fields};
+use crate::util::csrf::{self, CSRF_FIELD};
+use crate::util::db::update;
+use crate::util::files_origin::{self, FileGrant};
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::manage;
+use crate::util::misc::{now, remove_expired, unlocked_copy};
+use crate::util::share::{self, Share, MAX_SHARES};
+use crate::{AppState, Pasta};
+
+#[derive(Template)]
+#[template(path = "shares.html")]
+struct SharesTemplate<'a> {
+    args: &'a Args,
+    pasta: &'a Pasta,
+    // only the uploader can share an upload
+    allowed: bool,
+    manage_token: &'a str,
+    status: &'a str,
+    // the link that was just created, shown once
+    new_url: Option<String>,
+    csrf_token: String,
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(ErrorTemplate { args: &ARGS }.render().unwrap())
+}
+
+fn intern_id(id: &str) -> u64 {
+    if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    }
+}
+
+fn render(
+    pasta: &Pasta,
+    req: &HttpRequest,
+    allowed: bool,
+    manage_token: &str,
+    status: &str,
+    new_url: Option<String>,
+) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        SharesTemplate {
+            args: &ARGS,
+            pasta,
+            allowed,
+            manage_token,
+            status,
+            new_url,
+            csrf_token: csrf::token(req),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+/// The page that lists the share links of an upload, for the management link too.
+pub fn shares_page(data: &AppState, req: &HttpRequest, id: &str, manage_token: &str) -> HttpResponse {
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let id = intern_id(id);
+    let index = match pastas.iter().position(|pasta| pasta.id == id) {
+        Some(index) if share::can_share(&pastas[index]) => index,
+        _ => return not_found(),
+    };
+
+    if share::remove_expired(&mut pastas[index], now()) {
+        update(Some(&pastas), Some(&pastas[index]));
+    }
+
+    let allowed = data.sessions.can_manage(req, &pastas[index])
+        || manage::token_matches(&pastas[index], manage_token);
+    render(&pastas[index], req, allowed, manage_token, "", None)
+}
+
+#[get("/shares/{id}")]
+pub async fn get_shares(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    shares_page(&data, &req, &id, "")
+}
+
+#[post("/shares/{id}")]
+pub async fn post_shares(
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: Multipart,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let [action, manage_token, password, label, valid_hours, max_uses, share_id, csrf_token] =
+        read_fields(
+            payload,
+            [
+                "action",
+                "manage_token",
+                "password",
+                "label",
+                "valid_hours",
+                "max_uses",
+                "share",
+                CSRF_FIELD,
+            ],
+        )
+        .await?;
+    csrf::verify(&req, &csrf_token)?;
+
+    let mut pastas = data.pastas.lock().unwrap();
+
+    remove_expired(&mut pastas);
+
+    let intern_id = intern_id(&id);
+    let mut index = match pastas.iter().position(|pasta| pasta.id == intern_id) {
+        Some(index) if share::can_share(&pastas[index]) => index,
+        _ => return Ok(not_found()),
+    };
+
+    let allowed = data.sessions.can_manage(&req, &pastas[index])
+        || manage::token_matches(&pastas[index], &manage_token);
+    if !allowed {
+        return Ok(render(&pastas[index], &req, false, &manage_token, "", None));
+    }
+
+    let timenow = now();
+    share::remove_expired(&mut pastas[index], timenow);
+
+    if action == "revoke" {
+        pastas[index].shares.retain(|share| share.id() != share_id);
+        update(Some(&pastas), Some(&pastas[index]));
+
+        let location = if manage_token.is_empty() {
+            format!("{}/shares/{}", ARGS.public_path_as_str(), pastas[index].id_as_animals())
+        } else {
+            format!("{}/shares", manage::url(&pastas[index].id_as_animals(), &manage_token))
+        };
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", location))
+            .finish());
+    }
+
+    if pastas[index].shares.len() >= MAX_SHARES {
+        return Ok(render(&pastas[index], &req, true, &manage_token, "limit", None));
+    }
+
+    // the link carries the password of an upload encrypted on the server, so it has to
+    // be the right one
+    let key = if pastas[index].encrypt_server {
+        let subjects = [
+            attempts::client_subject(&req),
+            attempts::upload_subject(&pastas[index].id_as_animals()),
+        ];
+        if data.attempts.is_locked(&subjects) {
+            return Ok(render(&pastas[index], &req, true, &manage_token, "locked", None));
+        }
+
+        let original = unlocked_copy(pastas, index);
+
+        if !auth::pasta_password_opens(&original, &password) {
+            data.attempts.record_failure(&subjects);
+            return Ok(render(&original, &req, true, &manage_token, "incorrect", None));
+        }
+        data.attempts.record_success(&subjects[1]);
+
+        // the password may have been changed in the meantime
+        pastas = data.pastas.lock().unwrap();
+        index = match pastas.iter().position(|pasta| pasta.id == intern_id) {
+            Some(index) if pastas[index].encrypt_server && pastas[index].content == original.content => index,
+            Some(index) => {
+                return Ok(render(&pastas[index], &req, true, &manage_token, "incorrect", None))
+            }
+            None => return Ok(not_found()),
+        };
+        if pastas[index].shares.len() >= MAX_SHARES {
+            return Ok(render(&pastas[index], &req, true, &manage_token, "limit", None));
+        }
+        Some(password.as_str())
+    } else {
+        None
+    };
+
+    let (share, token) = Share::new(
+        &label,
+        key,
+        timenow,
+        valid_hours.trim().parse().unwrap_or(0).max(0),
+        max_uses.trim().parse().unwrap_or(0),
+    );
+    pastas[index].shares.push(share);
+    update(Some(&pastas), Some(&pastas[index]));
+
+    Ok(render(&pastas[index], &req, true, &manage_token, "", Some(share::url(&token))))
+}
+
+#[get("/s/{token}")]
+pub async fn get_shared(
+    data: web::Data<AppState>,
+    token: web::Path<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (slug, share) = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        remove_expired(&mut pastas);
+
+        match share::find(&pastas, &token) {
+            Some((i, j)) if pastas[i].shares[j].can_open(now()) => {
+                (pastas[i].id_as_animals(), pastas[i].shares[j].clone())
+            }
+            _ => return not_found(),
+        }
+    };
+
+    let password = match share.unwrap_key(&token) {
+        Ok(password) => password,
+        Err(e) => {
+            log::error!("Failed to unwrap the key of a share link: {}", e);
+            return not_found();
+        }
+    };
+
+    let response = pastaresponse(
+        data.clone(),
+        web::Path::from(slug),
+        password.unwrap_or_default(),
+        false,
+        &req,
+        Some(&token),
+    );
+    // only views that were shown count, refused ones do not use the link up
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let mut pastas = data.pastas.lock().unwrap();
+    let timenow = now();
+    let (i, j) = match share::find(&pastas, &token) {
+        // somebody else may have used the last view in the meantime
+        Some((i, j)) if pastas[i].shares[j].can_open(timenow) => (i, j),
+        _ => return not_found(),
+    };
+    let share = &mut pastas[i].shares[j];
+    share.uses += 1;
+    share.last_used = timenow;
+    update(Some(&pastas), Some(&pastas[i]));
+
+    response
+}
+
+#[get("/s/{token}/file")]
+pub async fn get_shared_file(
+    data: web::Data<AppState>,
+    token: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let (slug, share) = {
+        let mut pastas = data.pastas.lock().unwrap();
+
+        remove_expired(&mut pastas);
+
+        let (i, j) = match share::find(&pastas, &token) {
+            Some((i, j)) if pastas[i].shares[j].can_download(now()) => (i, j),
+            _ => return Err(ErrorForbidden("This share link has expired.")),
+        };
+
+        (pastas[i].id_as_animals(), pastas[i].shares[j].clone())
+    };
+
+    let password = share
+        .unwrap_key(&token)
+        .map_err(|_| ErrorForbidden("This share link has expired."))?;
+
+    if let Some(denied) = access::denied_for(&data, &req, &slug) {
+        return Ok(denied);
+    }
+
+    let preview = query.get("preview").map(|s| s == "true").unwrap_or(false);
+
+    if files_origin::should_redirect(&req) {
+        return Ok(FileGrant::file(&slug, query.get("fname"), preview, password).redirect());
+    }
+
+    match password {
+        Some(password) => secure_file_response(req, data, &slug, query.get("fname"), password).await,
+        None => file_response(&req, &data, &slug, query.get("fname"), preview),
+    }
+}
@@ -2,13 +2,20 @@ extern crate core;
 
 use crate::args::ARGS;
 use crate::endpoints::{
-    admin, archive, auth_admin, auth_upload, create, edit, errors, file, guide, list,
-    pasta as pasta_endpoint, qr, remove, static_resources,
+    account, admin, archive, auth_admin, auth_upload, create, edit, errors, expiry, file, guide,
+    list, manage, mine, oidc, pasta as pasta_endpoint, privacy, qr, reencrypt, remove, share, static_resources,
 };
 use crate::pasta::Pasta;
+use crate::util::attempts::AttemptTracker;
 use crate::util::db::read_all;
+use crate::util::listen::Listener;
+use crate::util::oidc::Oidc;
+use crate::util::session::SessionStore;
+use crate::util::two_factor::TwoFactor;
+use crate::util::users::UserStore;
 use crate::util::telemetry::start_telemetry_thread;
 use actix_web::middleware::Condition;
+use actix_web::dev::Service;
 use actix_web::{middleware, web, App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use chrono::Local;
@@ -16,27 +23,46 @@ use env_logger::Builder;
 use log::LevelFilter;
 use std::fs;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::sync::Mutex;
 
 pub mod args;
 pub mod pasta;
 
 pub mod util {
+    pub mod access;
     pub mod animalnumbers;
+    pub mod attempts;
     pub mod auth;
+    pub mod crypto;
+    pub mod csrf;
     pub mod db;
     pub mod db_json;
     #[cfg(feature = "default")]
     pub mod db_sqlite;
+    pub mod expiry;
+    pub mod files_origin;
     pub mod hashids;
+    pub mod listen;
+    pub mod manage;
     pub mod misc;
+    pub mod oidc;
+    pub mod proxy_auth;
+    pub mod recent;
+    pub mod security_headers;
+    pub mod session;
+    pub mod share;
     pub mod syntaxhighlighter;
     pub mod telemetry;
+    pub mod tls;
+    pub mod two_factor;
+    pub mod users;
     pub mod version;
     pub mod http_client;
 }
 
 pub mod endpoints {
+    pub mod account;
     pub mod admin;
     pub mod archive;
     pub mod auth_admin;
@@ -44,17 +70,29 @@ pub mod endpoints {
     pub mod create;
     pub mod edit;
     pub mod errors;
+    pub mod expiry;
     pub mod file;
     pub mod guide;
     pub mod list;
+    pub mod manage;
+    pub mod mine;
+    pub mod oidc;
     pub mod pasta;
+    pub mod privacy;
     pub mod qr;
+    pub mod reencrypt;
     pub mod remove;
+    pub mod share;
     pub mod static_resources;
 }
 
 pub struct AppState {
     pub pastas: Mutex<Vec<Pasta>>,
+    pub attempts: AttemptTracker,
+    pub sessions: SessionStore,
+    pub two_factor: TwoFactor,
+    pub users: UserStore,
+    pub oidc: Oidc,
 }
 
 #[actix_web::main]
@@ -72,12 +110,6 @@ async fn main() -> std::io::Result<()> {
         .filter(None, LevelFilter::Info)
         .init();
 
-    log::info!(
-        "MicroBin starting on http://{}:{}",
-        ARGS.bind.to_string(),
-        ARGS.port.to_string()
-    );
-
     match fs::create_dir_all(format!("{}/public", ARGS.data_dir)) {
         Ok(dir) => dir,
         Err(error) => {
@@ -95,16 +127,39 @@ async fn main() -> std::io::Result<()> {
 
     let data = web::Data::new(AppState {
         pastas: Mutex::new(read_all()),
+        attempts: AttemptTracker::default(),
+        sessions: SessionStore::default(),
+        two_factor: TwoFactor::load(),
+        users: UserStore::load(),
+        oidc: Oidc::default(),
     });
 
+    util::oidc::check_config();
+    util::proxy_auth::check_config();
+    util::tls::check_config();
+    util::listen::check_config();
+
     if !ARGS.disable_telemetry {
         start_telemetry_thread();
     }
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .wrap(Condition::new(
+                ARGS.files_origin.is_some(),
+                middleware::from_fn(util::files_origin::only_files),
+            ))
             .wrap(middleware::NormalizePath::trim())
+            .wrap_fn(|req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    util::csrf::set_cookie(&mut response);
+                    util::security_headers::add_headers(&mut response);
+                    Ok(response)
+                }
+            })
             .wrap(
                 middleware::Logger::new(r#"%{r}a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
             // `%{r}a` is actix's built‑in "real ip" token, which uses
@@ -122,8 +177,11 @@ async fn main() -> std::io::Result<()> {
             .service(pasta_endpoint::shortredirecturl)
             .service(qr::getqr)
             .service(file::get_file)
+            .service(file::get_file_with_token)
             .service(archive::get_archive)
             .service(file::post_secure_file)
+            .service(share::get_shared)
+            .service(share::get_shared_file)
             .service(static_resources::static_resources)
             .service(guide::guide)
             .service(auth_upload::auth_file_with_status)
@@ -136,15 +194,29 @@ async fn main() -> std::io::Result<()> {
             .service(auth_upload::auth_raw_pasta)
             .service(auth_upload::auth_edit_private)
             .service(auth_upload::auth_remove_private)
+            .service(oidc::get_oidc_login)
+            .service(oidc::get_oidc_callback)
             // Protected Services (Require Login)
             .service(
                 web::scope("")
                     .wrap(Condition::new(
                         ARGS.auth_basic_username.is_some()
-                            && ARGS.auth_basic_username.as_ref().unwrap().trim() != "",
+                            && ARGS.auth_basic_username.as_ref().unwrap().trim() != ""
+                            && !util::oidc::is_enabled()
+                            && !util::proxy_auth::is_enabled(),
                         HttpAuthentication::basic(util::auth::auth_validator),
                     ))
+                    .wrap(Condition::new(
+                        util::oidc::is_enabled(),
+                        middleware::from_fn(util::oidc::require_session),
+                    ))
+                    // runs first, so single sign-on lets in whoever the proxy names
+                    .wrap(Condition::new(
+                        util::proxy_auth::is_enabled(),
+                        middleware::from_fn(util::proxy_auth::authenticate),
+                    ))
                     .service(create::index)
+                    .service(create::get_fork)
                     .service(auth_admin::auth_admin)
                     .service(auth_admin::auth_admin)
                     .service(auth_admin::auth_admin_with_status)
@@ -154,17 +226,86 @@ async fn main() -> std::io::Result<()> {
                     .service(edit::post_edit_private)
                     .service(edit::post_submit_edit_private)
                     .service(admin::get_admin)
-                    .service(admin::post_admin)
+                    .service(admin::post_admin_login)
+                    .service(admin::post_admin_logout)
+                    .service(admin::get_two_factor)
+                    .service(admin::post_enable_two_factor)
+                    .service(admin::post_regenerate_recovery_codes)
+                    .service(admin::post_disable_two_factor)
+                    .service(admin::post_clear_lockouts)
+                    .service(admin::get_users)
+                    .service(admin::get_users_with_status)
+                    .service(admin::post_create_user)
+                    .service(admin::post_reset_user_password)
+                    .service(admin::post_remove_user)
+                    .service(reencrypt::get_reencrypt)
+                    .service(reencrypt::get_reencrypt_with_status)
+                    .service(reencrypt::post_reencrypt)
+                    .service(expiry::get_expiry)
+                    .service(expiry::get_expiry_with_status)
+                    .service(expiry::post_expiry)
                     .service(remove::remove)
                     .service(remove::post_remove)
+                    .service(privacy::get_privacy)
+                    .service(privacy::get_privacy_with_status)
+                    .service(privacy::post_privacy)
                     .service(list::list)
+                    .service(mine::mine)
+                    .service(manage::get_manage)
+                    .service(manage::get_manage_edit)
+                    .service(manage::get_manage_expiry)
+                    .service(manage::get_manage_expiry_with_status)
+                    .service(manage::get_manage_remove)
+                    .service(manage::get_manage_shares)
+                    .service(manage::get_manage_privacy)
+                    .service(manage::get_manage_privacy_with_status)
+                    .service(share::get_shares)
+                    .service(share::post_shares)
+                    .service(account::get_login)
+                    .service(account::get_login_with_status)
+                    .service(account::post_login)
+                    .service(account::post_logout)
+                    .service(account::get_my_uploads)
+                    .service(account::get_my_uploads_with_status)
+                    .service(account::post_change_password)
                     .service(web::resource("/upload").route(web::post().to(create::create)))
                     .service(create::index_with_status)
             )
             .default_service(web::route().to(errors::not_found))
-    })
-    .bind((ARGS.bind, ARGS.port))?
-    .workers(ARGS.threads as usize)
-    .run()
-    .await
+    });
+
+    let tls_config = util::tls::is_enabled().then(util::tls::server_config);
+    for listener in util::listen::listeners()? {
+        log::info!("MicroBin starting on {}", listener);
+        server = match listener {
+            Listener::Tcp(listener) => match &tls_config {
+                Some(tls_config) => server.listen_rustls_0_23(listener, tls_config.clone())?,
+                None => server.listen(listener)?,
+            },
+            // Unix sockets are spoken to by a proxy on the same machine, in plain HTTP
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                if ARGS.trust_unix_socket {
+                    log::info!("Requests over the Unix socket are trusted as coming from a reverse proxy: its X-Forwarded-For and authentication headers are believed.");
+                }
+                server.listen_uds(listener)?
+            }
+        };
+    }
+    let server = server.workers(ARGS.threads as usize).run();
+
+    match ARGS.tls_redirect_port {
+        Some(port) => {
+            let mut redirect = HttpServer::new(|| {
+                App::new().default_service(web::route().to(util::tls::redirect_to_https))
+            });
+            for bind in &ARGS.bind {
+                let listener = util::listen::tcp(SocketAddr::new(*bind, port))?;
+                log::info!("Redirecting http://{} to HTTPS", listener.local_addr()?);
+                redirect = redirect.listen(listener)?;
+            }
+            futures::try_join!(server, redirect.workers(1).run()).map(|_| ())
+        }
+        None => server.await,
+    }
 }
@@ -1,5 +1,6 @@
 use bytesize::ByteSize;
 use chrono::{Datelike, Local, TimeZone, Timelike};
+use ipnet::IpNet;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -8,7 +9,9 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::args::ARGS;
 use crate::util::animalnumbers::to_animal_names;
+use crate::util::crypto::{file_is_sealed, is_legacy_text};
 use crate::util::hashids::to_hashids;
+use crate::util::share::Share;
 use crate::util::syntaxhighlighter::html_highlight;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Eq, Clone)]
@@ -36,6 +39,10 @@ impl PastaFile {
         utf8_percent_encode(&self.name, NON_ALPHANUMERIC).to_string()
     }
 
+    pub fn name_attribute_safe(&self) -> String {
+        html_escape::encode_double_quoted_attribute(&self.name).to_string()
+    }
+
     pub fn display_name(&self) -> &str {
         self.name.strip_suffix(".enc").unwrap_or(&self.name)
     }
@@ -70,7 +77,7 @@ impl PastaFile {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pasta {
     pub id: u64,
     pub content: String,
@@ -90,6 +97,27 @@ pub struct Pasta {
     pub read_count: u64,
     pub burn_after_reads: u64,
     pub pasta_type: String,
+    #[serde(default)]
+    pub version: u64,
+    #[serde(default)]
+    pub parent: Option<u64>,
+    #[serde(default)]
+    pub encrypt_fragment: bool,
+    // the user who created the upload while signed in
+    #[serde(default)]
+    pub owner: Option<u64>,
+    // hash of the token of the management link, see util::manage
+    #[serde(default)]
+    pub manage_token_hash: Option<String>,
+    // links that let others view the upload, see util::share
+    #[serde(default)]
+    pub shares: Vec<Share>,
+    // address ranges the upload can be reached from, any if empty; see util::access
+    #[serde(default)]
+    pub allowed_networks: Vec<IpNet>,
+    // whether only signed in visitors can see the upload
+    #[serde(default)]
+    pub require_login: bool,
 }
 
 impl Pasta {
@@ -101,10 +129,90 @@ impl Pasta {
         }
     }
 
+    pub fn parent_as_animals(&self) -> Option<String> {
+        self.parent.map(|parent| {
+            if ARGS.hash_ids {
+                to_hashids(parent)
+            } else {
+                to_animal_names(parent)
+            }
+        })
+    }
+
+    // encrypted uploads cannot be read by the server, so they cannot be copied
+    pub fn forkable(&self) -> bool {
+        !self.encrypt_server && !self.encrypt_client && !self.encrypt_fragment
+    }
+
+    pub fn has_access_rules(&self) -> bool {
+        !self.allowed_networks.is_empty() || self.require_login
+    }
+
+    pub fn allowed_networks_as_string(&self) -> String {
+        self.allowed_networks
+            .iter()
+            .map(|net| net.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
     pub fn has_file(&self) -> bool {
         self.file.is_some()
     }
 
+    pub fn all_files(&self) -> Vec<&PastaFile> {
+        let mut files: Vec<&PastaFile> = Vec::new();
+        if let Some(file) = &self.file {
+            files.push(file);
+        }
+        if let Some(attachments) = &self.attachments {
+            files.extend(attachments.iter());
+        }
+        files
+    }
+
+    // the first file becomes the primary file, the rest are stored as attachments
+    pub fn set_files(&mut self, mut files: Vec<PastaFile>) {
+        if files.is_empty() {
+            self.file = None;
+            self.attachments = None;
+            return;
+        }
+        self.file = Some(files.remove(0));
+        self.attachments = if files.is_empty() { None } else { Some(files) };
+    }
+
+    // path of a stored file, server-side encrypted files carry an extra .enc suffix
+    pub fn stored_file_path(&self, name: &str) -> String {
+        let dir = format!("{}/attachments/{}", ARGS.data_dir, self.id_as_animals());
+        if self.encrypt_server {
+            let path = format!("{}/{}.enc", dir, name);
+            // single files of older uploads were stored as data.enc
+            let legacy = format!("{}/data.enc", dir);
+            if !Path::new(&path).exists() && Path::new(&legacy).exists() {
+                return legacy;
+            }
+            path
+        } else {
+            format!("{}/{}", dir, name)
+        }
+    }
+
+    // whether anything of the upload is still encrypted in the format of older releases
+    pub fn uses_legacy_encryption(&self) -> bool {
+        if self.readonly {
+            return self.encrypted_key.as_deref().is_some_and(is_legacy_text);
+        }
+        if !self.encrypt_server {
+            return false;
+        }
+        is_legacy_text(&self.content)
+            || self
+                .all_files()
+                .iter()
+                .any(|file| !file_is_sealed(&self.stored_file_path(file.name())).unwrap_or(true))
+    }
+
     pub fn total_size_as_string(&self) -> String {
         let mut total_size_bytes = self.content.as_bytes().len();
         if let Some(file) = &self.file {
@@ -130,7 +238,7 @@ impl Pasta {
     pub fn file_embeddable(&self) -> bool {
         return self.has_file()
             && self.file.as_ref().unwrap().embeddable()
-            && !(self.encrypt_server || self.encrypt_client);
+            && !(self.encrypt_server || self.encrypt_client || self.encrypt_fragment);
     }
 
     pub fn created_as_string(&self) -> String {
@@ -167,6 +275,31 @@ impl Pasta {
         }
     }
 
+    pub fn expires_in_as_string(&self) -> String {
+        if self.expiration == 0 {
+            return String::from("Never");
+        }
+
+        let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_secs(),
+            Err(_) => {
+                log::error!("SystemTime before UNIX EPOCH!");
+                0
+            }
+        } as i64;
+
+        let seconds = self.expiration - timenow;
+        if seconds >= 2 * 86400 {
+            format!("in {} days", seconds / 86400)
+        } else if seconds >= 2 * 3600 {
+            format!("in {} hours", seconds / 3600)
+        } else if seconds >= 120 {
+            format!("in {} minutes", seconds / 60)
+        } else {
+            String::from("in a moment")
+        }
+    }
+
     pub fn last_read_time_ago_as_string(&self) -> String {
         // get current unix time in seconds
         let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
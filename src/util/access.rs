@@ -0,0 +1,235 @@
+//! Access rules of single uploads: the address ranges they can be reached from, and
+//! whether only people who signed in can see them. The rules apply to every page and
+//! file of the upload, share links included. Its signed in owner and the admin are never
+//! locked out of it.
+
+use crate::args::ARGS;
+use crate::pasta::Pasta;
+use crate::util::animalnumbers::to_u64;
+use crate::util::hashids::to_u64 as hashid_to_u64;
+use crate::util::proxy_auth;
+use crate::AppState;
+use actix_web::http::header::{self, Header};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use ipnet::IpNet;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::net::{IpAddr, SocketAddr};
+
+pub enum Denied {
+    // the client address is in none of the allowed ranges
+    Network,
+    // the upload needs a signed in visitor
+    Login,
+}
+
+/// Parses a list of address ranges separated by commas or whitespace. Single
+/// addresses are taken as ranges of one.
+pub fn parse_networks(value: &str) -> Result<Vec<IpNet>, String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|network| !network.is_empty())
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map(|net| net.trunc())
+                .map_err(|_| format!("Invalid address range: {}", network))
+        })
+        .collect()
+}
+
+/// Whether signing in is possible on this server at all.
+pub fn login_available() -> bool {
+    basic_auth_enabled()
+        || ARGS.enable_users
+        || crate::util::oidc::is_enabled()
+        || proxy_auth::is_enabled()
+}
+
+fn basic_auth_enabled() -> bool {
+    ARGS.auth_basic_username
+        .as_ref()
+        .is_some_and(|username| username.trim() != "")
+        && ARGS.auth_basic_password.is_some()
+}
+
+fn is_trusted_proxy(ip: &IpAddr) -> bool {
+    ARGS.trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+// an address of X-Forwarded-For, which some proxies write with a port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// The client behind a chain of proxies. Each proxy appends the address it was
+/// reached from, so the hops are walked from the right and the first one that is
+/// not a trusted proxy is the client. Anything further left came from the client.
+/// A peer without an address connected over the Unix socket, which is only taken for
+/// a proxy when `trust_unix` is set.
+fn forwarded_client<'a>(
+    peer: Option<IpAddr>,
+    forwarded_for: impl Iterator<Item = &'a str>,
+    is_trusted: impl Fn(&IpAddr) -> bool,
+    trust_unix: bool,
+) -> Option<IpAddr> {
+    let hops: Vec<&str> = forwarded_for.flat_map(|value| value.split(',')).collect();
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !client.map_or(trust_unix, |ip| is_trusted(&ip)) {
+            break;
+        }
+        match parse_hop(hop) {
+            Some(ip) => client = Some(ip),
+            // a proxy would not have written this, so it is where the chain breaks
+            None => break,
+        }
+    }
+    client
+}
+
+/// The address of the client. Forwarded addresses are only believed from the
+/// trusted proxies, anyone else could claim to be in any range.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip().to_canonical());
+    let forwarded_for = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok());
+    forwarded_client(peer, forwarded_for, is_trusted_proxy, ARGS.trust_unix_socket)
+}
+
+fn has_basic_auth(req: &HttpRequest) -> bool {
+    let (Some(username), Some(password)) = (&ARGS.auth_basic_username, &ARGS.auth_basic_password)
+    else {
+        return false;
+    };
+    match Authorization::<Basic>::parse(req) {
+        Ok(auth) => {
+            let creds = auth.as_ref();
+            username.verify(creds.user_id())
+                && creds.password().is_some_and(|given| password.verify(given))
+        }
+        Err(_) => false,
+    }
+}
+
+fn is_signed_in(data: &AppState, req: &HttpRequest) -> bool {
+    data.sessions.principal(req).is_some()
+        || proxy_auth::principal(req, data).is_some()
+        || (basic_auth_enabled() && has_basic_auth(req))
+}
+
+/// Whether the request may see the upload.
+pub fn check(data: &AppState, req: &HttpRequest, pasta: &Pasta) -> Result<(), Denied> {
+    if !pasta.has_access_rules() || data.sessions.can_manage(req, pasta) {
+        return Ok(());
+    }
+
+    if !pasta.allowed_networks.is_empty() {
+        let allowed = client_ip(req)
+            .is_some_and(|ip| pasta.allowed_networks.iter().any(|net| net.contains(&ip)));
+        if !allowed {
+            return Err(Denied::Network);
+        }
+    }
+
+    if pasta.require_login && !is_signed_in(data, req) {
+        return Err(Denied::Login);
+    }
+
+    Ok(())
+}
+
+/// The answer to a request the rules of the upload keep out: those who could sign in
+/// are asked to.
+pub fn denied_response(req: &HttpRequest, denied: Denied) -> HttpResponse {
+    match denied {
+        Denied::Network => HttpResponse::Forbidden()
+            .content_type("text/plain; charset=utf-8")
+            .body("This upload cannot be reached from your network."),
+        Denied::Login if crate::util::oidc::is_enabled() => HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!(
+                    "{}/oidc/login?next={}",
+                    ARGS.public_path_as_str(),
+                    utf8_percent_encode(&req.uri().to_string(), NON_ALPHANUMERIC)
+                ),
+            ))
+            .finish(),
+        Denied::Login if basic_auth_enabled() => HttpResponse::Unauthorized()
+            .append_header((header::WWW_AUTHENTICATE, "Basic realm=\"MicroBin\""))
+            .content_type("text/plain; charset=utf-8")
+            .body("Please sign in to see this upload."),
+        Denied::Login if ARGS.enable_users => HttpResponse::Found()
+            .append_header(("Location", format!("{}/login", ARGS.public_path_as_str())))
+            .finish(),
+        Denied::Login => HttpResponse::Forbidden()
+            .content_type("text/plain; charset=utf-8")
+            .body("Please sign in to see this upload."),
+    }
+}
+
+/// The denial for the upload of the id, if there is one. Files and archives are checked
+/// here before they are handed out, also through the files origin, whose grants are
+/// only issued past this check.
+pub fn denied_for(data: &AppState, req: &HttpRequest, id: &str) -> Option<HttpResponse> {
+    let id = if ARGS.hash_ids {
+        hashid_to_u64(id).unwrap_or(0)
+    } else {
+        to_u64(id).unwrap_or(0)
+    };
+
+    let pastas = data.pastas.lock().unwrap();
+    let pasta = pastas.iter().find(|pasta| pasta.id == id)?;
+    check(data, req, pasta)
+        .err()
+        .map(|denied| denied_response(req, denied))
+}
+
+#[test]
+fn test_parse_networks() {
+    let networks = parse_networks("10.8.0.0/16, 192.168.1.7\n2001:db8::/32").unwrap();
+    assert_eq!(networks.len(), 3);
+    assert!(networks[0].contains(&"10.8.200.1".parse::<IpAddr>().unwrap()));
+    assert!(!networks[0].contains(&"10.9.0.1".parse::<IpAddr>().unwrap()));
+    assert_eq!(networks[1].to_string(), "192.168.1.7/32");
+    assert!(parse_networks("").unwrap().is_empty());
+    assert!(parse_networks("10.0.0.0/33").is_err());
+}
+
+#[test]
+fn test_forwarded_client() {
+    let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+    let proxies = parse_networks("10.0.0.0/8").unwrap();
+    let is_trusted = |ip: &IpAddr| proxies.iter().any(|net| net.contains(ip));
+
+    // a client that is not a proxy cannot claim another address
+    let client = forwarded_client(Some(ip("203.0.113.5")), ["10.0.0.9"].into_iter(), is_trusted, false);
+    assert_eq!(client, Some(ip("203.0.113.5")));
+
+    // the address the client put in front is skipped
+    let hops = ["192.168.1.1, 203.0.113.5, 10.0.0.2"].into_iter();
+    assert_eq!(forwarded_client(Some(ip("10.0.0.1")), hops, is_trusted, false), Some(ip("203.0.113.5")));
+
+    // several header lines, one with a port
+    let hops = ["198.51.100.7", "203.0.113.5:4711"].into_iter();
+    assert_eq!(forwarded_client(Some(ip("10.0.0.1")), hops, is_trusted, false), Some(ip("203.0.113.5")));
+
+    let hops = ["not an address"].into_iter();
+    assert_eq!(forwarded_client(Some(ip("10.0.0.1")), hops, is_trusted, false), Some(ip("10.0.0.1")));
+
+    // over the Unix socket the proxy has no address, its header is only believed on request
+    let hops = ["192.168.1.1, 203.0.113.5"].into_iter();
+    assert_eq!(forwarded_client(None, hops, is_trusted, true), Some(ip("203.0.113.5")));
+    let hops = ["192.168.1.1, 203.0.113.5"].into_iter();
+    assert_eq!(forwarded_client(None, hops, is_trusted, false), None);
+    assert_eq!(forwarded_client(None, std::iter::empty(), is_trusted, true), None);
+}
@@ -0,0 +1,148 @@
+//! Counts failed password attempts per client IP and per target, such as an upload or
+//! the admin login. Once a subject failed too often, it is locked out for a period
+//! that doubles with every further failure.
+
+use crate::args::ARGS;
+use crate::util::access;
+use crate::util::misc::now;
+use actix_web::HttpRequest;
+use chrono::{Local, TimeZone};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// failures are forgotten once a subject stayed quiet for this long
+const FORGET_AFTER_SECS: i64 = 24 * 60 * 60;
+const MAX_LOCKOUT_SECS: i64 = 24 * 60 * 60;
+
+pub const ADMIN_SUBJECT: &str = "Admin login";
+pub const UPLOADER_SUBJECT: &str = "Uploader password";
+
+struct Attempts {
+    failures: u32,
+    last_failure: i64,
+    locked_until: i64,
+}
+
+pub struct Lockout {
+    pub subject: String,
+    pub failures: u32,
+    pub locked_until: i64,
+}
+
+impl Lockout {
+    pub fn is_locked(&self) -> bool {
+        self.locked_until > now()
+    }
+
+    pub fn locked_until_as_string(&self) -> String {
+        Local
+            .timestamp_opt(self.locked_until, 0)
+            .earliest()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+pub struct AttemptTracker {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+/// The client address, forwarded ones only from the trusted proxies, so nobody can get
+/// out of a lockout by claiming another address.
+pub fn client_subject(req: &HttpRequest) -> String {
+    match access::client_ip(req) {
+        Some(ip) => format!("IP {}", ip),
+        None => String::from("IP unknown"),
+    }
+}
+
+pub fn upload_subject(slug: &str) -> String {
+    format!("Upload {}", slug)
+}
+
+pub fn user_subject(username: &str) -> String {
+    format!("User {}", username.trim().to_lowercase())
+}
+
+impl AttemptTracker {
+    /// Whether any of the subjects is currently locked out.
+    pub fn is_locked(&self, subjects: &[String]) -> bool {
+        let now = now();
+        let attempts = self.attempts.lock().unwrap();
+        subjects.iter().any(|subject| {
+            attempts
+                .get(subject)
+                .is_some_and(|entry| entry.locked_until > now)
+        })
+    }
+
+    pub fn record_failure(&self, subjects: &[String]) {
+        if ARGS.max_login_attempts == 0 {
+            return;
+        }
+
+        let now = now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, entry| {
+            entry.locked_until > now || entry.last_failure + FORGET_AFTER_SECS > now
+        });
+
+        for subject in subjects {
+            let entry = attempts.entry(subject.to_owned()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: 0,
+            });
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.failures >= ARGS.max_login_attempts {
+                let doublings = (entry.failures - ARGS.max_login_attempts).min(20);
+                let lockout = (ARGS.lockout_seconds as i64)
+                    .saturating_mul(1 << doublings)
+                    .min(MAX_LOCKOUT_SECS);
+                entry.locked_until = now + lockout;
+                log::warn!(
+                    "{} locked out for {} seconds after {} failed attempts",
+                    subject,
+                    lockout,
+                    entry.failures
+                );
+            }
+        }
+    }
+
+    /// Forgets the failures of a target once its password was entered correctly. Client
+    /// IPs are not reset, so one known password cannot be used to guess others.
+    pub fn record_success(&self, subject: &str) {
+        self.attempts.lock().unwrap().remove(subject);
+    }
+
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let mut lockouts: Vec<Lockout> = self
+            .attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(subject, entry)| Lockout {
+                subject: subject.to_owned(),
+                failures: entry.failures,
+                locked_until: entry.locked_until,
+            })
+            .collect();
+        lockouts.sort_by_key(|lockout| std::cmp::Reverse(lockout.locked_until));
+        lockouts
+    }
+
+    /// Clears one subject, or all of them.
+    pub fn clear(&self, subject: Option<&str>) {
+        let mut attempts = self.attempts.lock().unwrap();
+        match subject {
+            Some(subject) => {
+                attempts.remove(subject);
+            }
+            None => attempts.clear(),
+        }
+    }
+}
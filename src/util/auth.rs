@@ -6,6 +6,10 @@ use actix_web_httpauth::extractors::basic::BasicAuth;
 use futures::TryStreamExt;
 
 use crate::args::ARGS;
+use crate::util::crypto::StreamDecryptor;
+use crate::util::misc::{decrypt, decrypt_file};
+use crate::Pasta;
+use std::fs::File;
 
 pub async fn auth_validator(
     req: ServiceRequest,
@@ -17,7 +21,7 @@ pub async fn auth_validator(
         creds.password(),
     ) {
         (Some(conf_user), Some(conf_pwd), Some(cred_pwd))
-            if creds.user_id() == **conf_user && **conf_pwd == cred_pwd =>
+            if conf_user.verify(creds.user_id()) && conf_pwd.verify(cred_pwd) =>
         {
             Ok(req)
         }
@@ -25,6 +29,24 @@ pub async fn auth_validator(
     }
 }
 
+/// Reads the named text fields of a multipart form, in the order given.
+pub async fn read_fields<const N: usize>(
+    mut payload: Multipart,
+    names: [&str; N],
+) -> Result<[String; N], Error> {
+    let mut values: [String; N] = std::array::from_fn(|_| String::new());
+    while let Some(mut field) = payload.try_next().await? {
+        let index = match names.iter().position(|name| field.name() == Some(*name)) {
+            Some(index) => index,
+            None => continue,
+        };
+        while let Some(chunk) = field.try_next().await? {
+            values[index].push_str(std::str::from_utf8(&chunk).unwrap_or_default());
+        }
+    }
+    Ok(values)
+}
+
 pub async fn password_from_multipart(mut payload: Multipart) -> Result<String, Error> {
     let mut password = String::new();
 
@@ -36,3 +58,66 @@ pub async fn password_from_multipart(mut payload: Multipart) -> Result<String, E
     }
     Ok(password)
 }
+
+// read-only, encrypted and non-editable uploads can only be changed with their password
+// (or the admin password)
+pub fn pasta_needs_password(pasta: &Pasta) -> bool {
+    pasta.encrypt_server || pasta.readonly || !pasta.editable
+}
+
+/// Whether the password is the one of the upload or the admin password.
+pub fn pasta_password_matches(pasta: &Pasta, password: &str) -> bool {
+    if password.is_empty() {
+        return false;
+    }
+
+    ARGS.auth_admin_password.verify(password) || pasta_password_opens(pasta, password)
+}
+
+/// Whether the upload still has the password it was checked against while the lock was
+/// released. Without a new version, only a new password changes the content.
+pub fn same_password(current: &Pasta, checked: &Pasta) -> bool {
+    current.encrypt_server == checked.encrypt_server
+        && current.readonly == checked.readonly
+        && current.encrypted_key == checked.encrypted_key
+        && (current.version != checked.version || current.content == checked.content)
+}
+
+/// Whether the password is the one of the upload itself, which is what decrypts it.
+/// Uploads of files only have no encrypted text to try it on, so their first file is
+/// tried instead.
+pub fn pasta_password_opens(pasta: &Pasta, password: &str) -> bool {
+    if password.is_empty() {
+        return false;
+    }
+
+    if pasta.readonly {
+        // if it is read-only, the content is not encrypted, but the key is
+        if let Some(ref encrypted_key) = pasta.encrypted_key {
+            if let Ok(decrypted_key) = decrypt(encrypted_key, password) {
+                return decrypted_key == pasta.id.to_string();
+            }
+        }
+        false
+    } else if pasta.encrypt_server {
+        // if it is not read-only, the content is encrypted
+        if !pasta.content.is_empty() {
+            return decrypt(&pasta.content, password).is_ok();
+        }
+
+        let file = match &pasta.file {
+            Some(file) => file,
+            None => return false,
+        };
+        match File::open(pasta.stored_file_path(file.name())) {
+            Ok(mut f) => match StreamDecryptor::new(&mut f, password) {
+                Ok(Some(_)) => true,
+                Ok(None) => decrypt_file(password, &f).is_ok(),
+                Err(_) => false,
+            },
+            Err(_) => false,
+        }
+    } else {
+        false
+    }
+}
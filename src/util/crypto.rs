@@ -0,0 +1,475 @@
+//! Server-side encryption of pasta contents, keys and files.
+//!
+//! Data is sealed with XChaCha20-Poly1305 under a key derived from the password with
+//! Argon2id. Every ciphertext starts with a small header naming the format version, so
+//! uploads encrypted by older releases with `magic_crypt` (AES-256-CBC without
+//! authentication) can still be opened, and re-encrypted by an admin.
+//!
+//! Text uses format version 2, a single sealed message. Files use version 3, which
+//! splits the plaintext into chunks of [`CHUNK_SIZE`] bytes that are sealed one by one,
+//! so files can be encrypted while they are uploaded and any byte range can be
+//! decrypted without reading the whole file. The nonce of each chunk is derived from
+//! its index and whether it is the last one, so chunks cannot be reordered, dropped
+//! or truncated unnoticed.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use magic_crypt::{new_magic_crypt, MagicCryptTrait};
+use rand::RngCore;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// text ciphertexts are base64 and carry this prefix; legacy ones are plain base64
+const TEXT_PREFIX: &str = "mb2$";
+
+// binary header: magic, version, Argon2 salt, nonce
+const MAGIC: &[u8] = b"MBENC";
+const VERSION: u8 = 2;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+// chunked format for files: magic, version, Argon2 salt, nonce prefix
+const STREAM_VERSION: u8 = 3;
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+const STREAM_HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_PREFIX_LEN;
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+// Argon2id parameters of format version 2, as recommended by OWASP
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Encryption,
+    // wrong password or tampered ciphertext
+    Decryption,
+    Malformed,
+    KeyDerivation,
+    Io(io::Error),
+}
+
+impl From<io::Error> for CryptoError {
+    fn from(e: io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::Encryption => write!(f, "Failed to encrypt"),
+            CryptoError::Decryption => write!(f, "Failed to decrypt"),
+            CryptoError::Malformed => write!(f, "Malformed ciphertext"),
+            CryptoError::KeyDerivation => write!(f, "Failed to derive key from password"),
+            CryptoError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32], CryptoError> {
+    let params = Params::new(
+        ARGON2_MEMORY_KIB,
+        ARGON2_ITERATIONS,
+        ARGON2_PARALLELISM,
+        Some(32),
+    )
+    .map_err(|_| CryptoError::KeyDerivation)?;
+
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|_| CryptoError::KeyDerivation)?;
+    Ok(key)
+}
+
+/// Whether the bytes were produced by [`seal`] or a [`StreamEncryptor`], as opposed to
+/// the legacy format.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.len() >= STREAM_HEADER_LEN.min(HEADER_LEN) && data.starts_with(MAGIC)
+}
+
+pub fn file_is_sealed(path: &str) -> std::io::Result<bool> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(is_sealed(&header))
+}
+
+pub fn seal(plaintext: &[u8], password: &str) -> Result<Vec<u8>, CryptoError> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce);
+
+    let key = derive_key(password, &salt)?;
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    // the header is authenticated too, so the version cannot be swapped out
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| CryptoError::Encryption)?;
+
+    header.extend_from_slice(&ciphertext);
+    Ok(header)
+}
+
+pub fn open(data: &[u8], password: &str) -> Result<Vec<u8>, CryptoError> {
+    if !is_sealed(data) {
+        return open_legacy(data, password);
+    }
+    match data[MAGIC.len()] {
+        VERSION => {}
+        STREAM_VERSION => return open_stream(data, password),
+        _ => return Err(CryptoError::Malformed),
+    }
+    if data.len() < HEADER_LEN {
+        return Err(CryptoError::Malformed);
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce = XNonce::from_slice(&header[MAGIC.len() + 1 + SALT_LEN..]);
+
+    let key = derive_key(password, salt)?;
+    XChaCha20Poly1305::new(&key.into())
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| CryptoError::Decryption)
+}
+
+fn chunk_nonce(prefix: &[u8], index: u64, last: bool) -> Result<XNonce, CryptoError> {
+    let index = u32::try_from(index).map_err(|_| CryptoError::Malformed)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Ok(XNonce::from(nonce))
+}
+
+/// Encrypts a file chunk by chunk while it is being written.
+pub struct StreamEncryptor {
+    cipher: XChaCha20Poly1305,
+    header: Vec<u8>,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    pub fn new(password: &str) -> Result<Self, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let mut header = Vec::with_capacity(STREAM_HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(STREAM_VERSION);
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce_prefix);
+
+        let key = derive_key(password, &salt)?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            header,
+            index: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    /// Has to be written before the output of [`Self::update`] and [`Self::finish`].
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = chunk_nonce(&self.header[MAGIC.len() + 1 + SALT_LEN..], self.index, last)?;
+        self.index += 1;
+        self.cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_| CryptoError::Encryption)
+    }
+
+    /// Returns the ciphertext of all chunks completed by `data`.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.buffer.extend_from_slice(data);
+        let mut output = Vec::new();
+        // a full chunk is held back until more data arrives, as only finish()
+        // knows which chunk is the last one
+        while self.buffer.len() > CHUNK_SIZE {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK_SIZE).collect();
+            output.extend(self.seal_chunk(&chunk, false)?);
+        }
+        Ok(output)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>, CryptoError> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.seal_chunk(&chunk, true)
+    }
+}
+
+/// Encrypts everything written to it into the inner writer, in the chunked format.
+/// [`Self::finish`] writes the last chunk and has to be called.
+pub struct SealWriter<W: Write> {
+    encryptor: StreamEncryptor,
+    inner: W,
+}
+
+impl<W: Write> SealWriter<W> {
+    pub fn new(mut inner: W, password: &str) -> Result<Self, CryptoError> {
+        let encryptor = StreamEncryptor::new(password)?;
+        inner.write_all(encryptor.header())?;
+        Ok(Self { encryptor, inner })
+    }
+
+    pub fn finish(mut self) -> Result<W, CryptoError> {
+        self.inner.write_all(&self.encryptor.finish()?)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let sealed = self.encryptor.update(data).map_err(io::Error::other)?;
+        self.inner.write_all(&sealed)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encrypts everything read from `reader` into `writer` without holding it in memory.
+pub fn seal_stream(
+    mut reader: impl Read,
+    writer: impl Write,
+    password: &str,
+) -> Result<(), CryptoError> {
+    let mut sealer = SealWriter::new(writer, password)?;
+    io::copy(&mut reader, &mut sealer)?;
+    sealer.finish()?;
+    Ok(())
+}
+
+/// Decrypts arbitrary ranges of a file in the chunked format.
+pub struct StreamDecryptor {
+    cipher: XChaCha20Poly1305,
+    header: Vec<u8>,
+    chunk_count: u64,
+    plaintext_len: u64,
+}
+
+impl StreamDecryptor {
+    /// Returns None if the file is not in the chunked format, and an error if the
+    /// password is wrong or the file is damaged.
+    pub fn new(file: &mut File, password: &str) -> Result<Option<Self>, CryptoError> {
+        let file_len = file.metadata()?.len();
+        let mut header = Vec::with_capacity(STREAM_HEADER_LEN);
+        file.seek(SeekFrom::Start(0))?;
+        (&mut *file)
+            .take(STREAM_HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+        if header.len() < STREAM_HEADER_LEN
+            || !header.starts_with(MAGIC)
+            || header[MAGIC.len()] != STREAM_VERSION
+        {
+            // leave the file as it was for callers falling back to other formats
+            file.seek(SeekFrom::Start(0))?;
+            return Ok(None);
+        }
+
+        let decryptor = Self::from_header(header, file_len - STREAM_HEADER_LEN as u64, password)?;
+        // decrypting the first chunk checks the password before anything is returned
+        decryptor.read_chunk(file, 0)?;
+        Ok(Some(decryptor))
+    }
+
+    fn from_header(header: Vec<u8>, ciphertext_len: u64, password: &str) -> Result<Self, CryptoError> {
+        let sealed_chunk = (CHUNK_SIZE + TAG_LEN) as u64;
+        let chunk_count = ciphertext_len.div_ceil(sealed_chunk);
+        let last_chunk = ciphertext_len - chunk_count.saturating_sub(1) * sealed_chunk;
+        if chunk_count == 0 || last_chunk < TAG_LEN as u64 {
+            return Err(CryptoError::Malformed);
+        }
+
+        let key = derive_key(password, &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN])?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            header,
+            chunk_count,
+            plaintext_len: ciphertext_len - chunk_count * TAG_LEN as u64,
+        })
+    }
+
+    pub fn plaintext_len(&self) -> u64 {
+        self.plaintext_len
+    }
+
+    fn open_chunk(&self, index: u64, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let nonce = chunk_nonce(
+            &self.header[MAGIC.len() + 1 + SALT_LEN..],
+            index,
+            index + 1 == self.chunk_count,
+        )?;
+        self.cipher
+            .decrypt(
+                &nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_| CryptoError::Decryption)
+    }
+
+    fn read_chunk(&self, file: &mut File, index: u64) -> Result<Vec<u8>, CryptoError> {
+        let sealed_chunk = (CHUNK_SIZE + TAG_LEN) as u64;
+        file.seek(SeekFrom::Start(STREAM_HEADER_LEN as u64 + index * sealed_chunk))?;
+        let mut ciphertext = Vec::with_capacity(sealed_chunk as usize);
+        (&mut *file).take(sealed_chunk).read_to_end(&mut ciphertext)?;
+        self.open_chunk(index, &ciphertext)
+    }
+
+    /// Decrypts the plaintext from `position` up to the end of its chunk or `end`
+    /// (exclusive), whichever comes first.
+    pub fn read_at(&self, file: &mut File, position: u64, end: u64) -> Result<Vec<u8>, CryptoError> {
+        let index = position / CHUNK_SIZE as u64;
+        let chunk = self.read_chunk(file, index)?;
+        let chunk_start = index * CHUNK_SIZE as u64;
+        let from = (position - chunk_start) as usize;
+        let to = ((end.min(self.plaintext_len) - chunk_start) as usize).min(chunk.len());
+        if from >= to {
+            return Err(CryptoError::Malformed);
+        }
+        Ok(chunk[from..to].to_vec())
+    }
+}
+
+fn open_stream(data: &[u8], password: &str) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < STREAM_HEADER_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (header, ciphertext) = data.split_at(STREAM_HEADER_LEN);
+    let decryptor = StreamDecryptor::from_header(header.to_vec(), ciphertext.len() as u64, password)?;
+
+    let mut plaintext = Vec::with_capacity(decryptor.plaintext_len() as usize);
+    for (index, chunk) in ciphertext.chunks(CHUNK_SIZE + TAG_LEN).enumerate() {
+        plaintext.extend(decryptor.open_chunk(index as u64, chunk)?);
+    }
+    Ok(plaintext)
+}
+
+fn open_legacy(data: &[u8], password: &str) -> Result<Vec<u8>, CryptoError> {
+    let mc = new_magic_crypt!(password, 256);
+    mc.decrypt_bytes_to_bytes(data)
+        .map_err(|_| CryptoError::Decryption)
+}
+
+/// Whether the text was encrypted with the legacy format.
+pub fn is_legacy_text(text: &str) -> bool {
+    !text.is_empty() && !text.starts_with(TEXT_PREFIX)
+}
+
+pub fn seal_text(text: &str, password: &str) -> Result<String, CryptoError> {
+    Ok(format!("{}{}", TEXT_PREFIX, BASE64.encode(seal(text.as_bytes(), password)?)))
+}
+
+pub fn open_text(text: &str, password: &str) -> Result<String, CryptoError> {
+    let plaintext = match text.strip_prefix(TEXT_PREFIX) {
+        Some(sealed) => {
+            let data = BASE64.decode(sealed).map_err(|_| CryptoError::Malformed)?;
+            if !is_sealed(&data) {
+                return Err(CryptoError::Malformed);
+            }
+            open(&data, password)?
+        }
+        None => {
+            let mc = new_magic_crypt!(password, 256);
+            return mc
+                .decrypt_base64_to_string(text)
+                .map_err(|_| CryptoError::Decryption);
+        }
+    };
+    String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)
+}
+
+#[test]
+fn test_seal_roundtrip() {
+    let sealed = seal_text("hello", "password").unwrap();
+    assert!(!is_legacy_text(&sealed));
+    assert_eq!(open_text(&sealed, "password").unwrap(), "hello");
+    assert!(open_text(&sealed, "wrong").is_err());
+}
+
+#[test]
+fn test_tampering_is_detected() {
+    let mut sealed = seal(b"hello", "password").unwrap();
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert!(open(&sealed, "password").is_err());
+}
+
+#[test]
+fn test_legacy_is_readable() {
+    let mc = new_magic_crypt!("password", 256);
+    let legacy = mc.encrypt_str_to_base64("hello");
+    assert!(is_legacy_text(&legacy));
+    assert_eq!(open_text(&legacy, "password").unwrap(), "hello");
+
+    let legacy_file = mc.encrypt_bytes_to_bytes(b"hello");
+    assert!(!is_sealed(&legacy_file));
+    assert_eq!(open(&legacy_file, "password").unwrap(), b"hello");
+}
+
+#[test]
+fn test_stream_roundtrip() {
+    let plaintext: Vec<u8> = (0..CHUNK_SIZE * 2 + 123).map(|i| i as u8).collect();
+    for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 2, plaintext.len()] {
+        let mut sealed = Vec::new();
+        seal_stream(&plaintext[..len], &mut sealed, "password").unwrap();
+        assert_eq!(open(&sealed, "password").unwrap(), &plaintext[..len]);
+        assert!(open(&sealed, "wrong").is_err());
+    }
+}
+
+#[test]
+fn test_stream_truncation_is_detected() {
+    let plaintext = vec![7u8; CHUNK_SIZE * 2];
+    let mut sealed = Vec::new();
+    seal_stream(&plaintext[..], &mut sealed, "password").unwrap();
+    sealed.truncate(STREAM_HEADER_LEN + CHUNK_SIZE + TAG_LEN);
+    assert!(open(&sealed, "password").is_err());
+}
@@ -0,0 +1,139 @@
+//! Cross-site request forgery protection. Every browser gets a random id in a cookie, and
+//! forms carry an HMAC of that id as a hidden field. A page on another site can make the
+//! browser submit a form, but it cannot read the cookie to compute the matching token.
+
+use crate::args::ARGS;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::ServiceResponse;
+use actix_web::error::ErrorForbidden;
+use actix_web::http::header;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const CSRF_COOKIE: &str = "microbin_csrf";
+pub const CSRF_FIELD: &str = "csrf_token";
+
+lazy_static! {
+    static ref KEY: [u8; 32] = {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    };
+}
+
+// the id handed out with the response when the request came without a cookie
+#[derive(Clone)]
+struct NewId(String);
+
+fn id_is_valid(id: &str) -> bool {
+    id.len() == 43 && URL_SAFE_NO_PAD.decode(id).is_ok()
+}
+
+fn mac(id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(KEY.as_slice()).expect("HMAC accepts any key length");
+    mac.update(id.as_bytes());
+    mac
+}
+
+fn request_id(req: &HttpRequest) -> Option<String> {
+    if let Some(NewId(id)) = req.extensions().get::<NewId>() {
+        return Some(id.clone());
+    }
+    req.cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|id| id_is_valid(id))
+}
+
+/// The token to put into the forms of a page. Starts a new id if the browser has none
+/// yet; the cookie for it is added to the response by `set_cookie`.
+pub fn token(req: &HttpRequest) -> String {
+    let id = match request_id(req) {
+        Some(id) => id,
+        None => {
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let id = URL_SAFE_NO_PAD.encode(bytes);
+            req.extensions_mut().insert(NewId(id.clone()));
+            id
+        }
+    };
+    URL_SAFE_NO_PAD.encode(mac(&id).finalize().into_bytes())
+}
+
+/// Whether the request has to carry a token. Requests with cookies could be sent by a
+/// browser on behalf of another site, and so could any request that says where it
+/// comes from, as browsers do for every form. Only requests with neither are exempt:
+/// they come from scripts such as curl, which carry no session another site could
+/// borrow, so there is nothing to forge.
+fn needs_token(req: &HttpRequest) -> bool {
+    req.headers().contains_key(header::COOKIE)
+        || req.headers().contains_key(header::ORIGIN)
+        || req.headers().contains_key("Sec-Fetch-Site")
+}
+
+/// Rejects a form unless it carries the token of this browser.
+pub fn verify(req: &HttpRequest, submitted: &str) -> Result<(), Error> {
+    if !needs_token(req) {
+        return Ok(());
+    }
+
+    let valid = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|id| id_is_valid(id))
+        .zip(URL_SAFE_NO_PAD.decode(submitted.trim()).ok())
+        .is_some_and(|(id, signature)| mac(&id).verify_slice(&signature).is_ok());
+
+    if valid {
+        Ok(())
+    } else {
+        log::warn!("Rejected a form submission to {} without a valid CSRF token.", req.path());
+        Err(ErrorForbidden(
+            "The form has expired or was submitted from another site. Please reload the page and try again.",
+        ))
+    }
+}
+
+/// Adds the cookie for an id started by `token` while handling the request.
+pub fn set_cookie<B>(res: &mut ServiceResponse<B>) {
+    let id = match res.request().extensions().get::<NewId>() {
+        Some(NewId(id)) => id.clone(),
+        None => return,
+    };
+
+    let cookie = Cookie::build(CSRF_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(ARGS.public_path_as_str().starts_with("https://"))
+        .finish();
+    if let Err(e) = res.response_mut().add_cookie(&cookie) {
+        log::error!("Failed to set the CSRF cookie: {}", e);
+    }
+}
+
+#[test]
+fn test_cookies_need_a_token() {
+    use actix_web::test::TestRequest;
+
+    let script = TestRequest::post().to_http_request();
+    assert!(verify(&script, "").is_ok());
+
+    // a session cookie is sent along with forms from other sites too
+    let session = TestRequest::post()
+        .cookie(Cookie::new(crate::util::session::SESSION_COOKIE, "abc"))
+        .to_http_request();
+    assert!(verify(&session, "").is_err());
+
+    let id = URL_SAFE_NO_PAD.encode([7u8; 32]);
+    let browser = TestRequest::post()
+        .cookie(Cookie::new(CSRF_COOKIE, id.clone()))
+        .to_http_request();
+    let token = URL_SAFE_NO_PAD.encode(mac(&id).finalize().into_bytes());
+    assert!(verify(&browser, &token).is_ok());
+}
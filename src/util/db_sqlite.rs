@@ -11,18 +11,10 @@ pub fn update_all(pastas: &[Pasta]) {
     rewrite_all_to_db(pastas);
 }
 
-pub fn rewrite_all_to_db(pasta_data: &[Pasta]) {
+fn open_connection() -> Connection {
     let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
         .expect("Failed to open SQLite database!");
 
-    conn.execute(
-        "
-        DROP TABLE IF EXISTS pasta;
-        );",
-        params![],
-    )
-    .expect("Failed to drop SQLite table for Pasta!");
-
     conn.execute(
         "
         CREATE TABLE IF NOT EXISTS pasta (
@@ -43,25 +35,69 @@ pub fn rewrite_all_to_db(pasta_data: &[Pasta]) {
             read_count INTEGER NOT NULL,
             burn_after_reads INTEGER NOT NULL,
             attachments TEXT,
-            pasta_type TEXT NOT NULL
+            pasta_type TEXT NOT NULL,
+            version INTEGER NOT NULL DEFAULT 0,
+            parent INTEGER,
+            encrypt_fragment INTEGER NOT NULL DEFAULT 0,
+            owner INTEGER,
+            manage_token_hash TEXT,
+            shares TEXT,
+            allowed_networks TEXT,
+            require_login INTEGER NOT NULL DEFAULT 0
         );",
         params![],
     )
     .expect("Failed to create SQLite table for Pasta!");
 
-    // Migration: Add attachments column if it doesn't exist
+    // Migrations: add columns introduced after the table was first created
     let _ = conn.execute("ALTER TABLE pasta ADD COLUMN attachments TEXT", params![]);
+    let _ = conn.execute(
+        "ALTER TABLE pasta ADD COLUMN version INTEGER NOT NULL DEFAULT 0",
+        params![],
+    );
+    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN parent INTEGER", params![]);
+    let _ = conn.execute(
+        "ALTER TABLE pasta ADD COLUMN encrypt_fragment INTEGER NOT NULL DEFAULT 0",
+        params![],
+    );
+    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN owner INTEGER", params![]);
+    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN manage_token_hash TEXT", params![]);
+    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN shares TEXT", params![]);
+    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN allowed_networks TEXT", params![]);
+    let _ = conn.execute(
+        "ALTER TABLE pasta ADD COLUMN require_login INTEGER NOT NULL DEFAULT 0",
+        params![],
+    );
+
+    conn
+}
+
+pub fn rewrite_all_to_db(pasta_data: &[Pasta]) {
+    let conn = open_connection();
+
+    conn.execute("DELETE FROM pasta;", params![])
+        .expect("Failed to clear SQLite table for Pasta!");
 
     for pasta in pasta_data.iter() {
-        conn.execute(
-            "INSERT INTO pasta (
+        insert_with(&conn, pasta);
+    }
+}
+
+pub fn select_all_from_db() -> Vec<Pasta> {
+    let conn = open_connection();
+
+    // Columns are listed explicitly because older databases gained some of
+    // them through ALTER TABLE, so their position differs from fresh ones.
+    let mut stmt = conn
+        .prepare(
+            "SELECT
                 id,
                 content,
                 file_name,
                 file_size,
                 extension,
-                private,
                 read_only,
+                private,
                 editable,
                 encrypt_server,
                 encrypt_client,
@@ -72,68 +108,17 @@ pub fn rewrite_all_to_db(pasta_data: &[Pasta]) {
                 read_count,
                 burn_after_reads,
                 pasta_type,
-                attachments
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
-            params![
-                pasta.id,
-                pasta.content,
-                pasta.file.as_ref().map_or("", |f| f.name.as_str()),
-                pasta.file.as_ref().map_or(0, |f| f.size.as_u64()),
-                pasta.extension,
-                pasta.private as i32,
-                pasta.readonly as i32,
-                pasta.editable as i32,
-                pasta.encrypt_server as i32,
-                pasta.encrypt_client as i32,
-                pasta.encrypted_key.as_deref(),
-                pasta.created,
-                pasta.expiration,
-                pasta.last_read,
-                pasta.read_count,
-                pasta.burn_after_reads,
-                pasta.pasta_type,
-                serde_json::to_string(&pasta.attachments).unwrap_or("".to_string()),
-            ],
+                attachments,
+                version,
+                parent,
+                encrypt_fragment,
+                owner,
+                manage_token_hash,
+                shares,
+                allowed_networks,
+                require_login
+            FROM pasta ORDER BY created ASC",
         )
-        .expect("Failed to insert pasta.");
-    }
-}
-
-pub fn select_all_from_db() -> Vec<Pasta> {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
-
-    conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS pasta (
-            id INTEGER PRIMARY KEY,
-            content TEXT NOT NULL,
-            file_name TEXT,
-            file_size INTEGER,
-            extension TEXT NOT NULL,
-            read_only INTEGER NOT NULL,
-            private INTEGER NOT NULL,
-            editable INTEGER NOT NULL,
-            encrypt_server INTEGER NOT NULL,
-            encrypt_client INTEGER NOT NULL,
-            encrypted_key TEXT,
-            created INTEGER NOT NULL,
-            expiration INTEGER NOT NULL,
-            last_read INTEGER NOT NULL,
-            read_count INTEGER NOT NULL,
-            burn_after_reads INTEGER NOT NULL,
-            attachments TEXT,
-            pasta_type TEXT NOT NULL
-        );",
-        params![],
-    )
-    .expect("Failed to create SQLite table for Pasta!");
-
-    // Migration: Add attachments column if it doesn't exist
-    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN attachments TEXT", params![]);
-
-    let mut stmt = conn
-        .prepare("SELECT * FROM pasta ORDER BY created ASC")
         .expect("Failed to prepare SQL statement to load pastas");
 
     let pasta_iter = stmt
@@ -171,6 +156,20 @@ pub fn select_all_from_db() -> Vec<Pasta> {
                     Ok(Some(json)) => serde_json::from_str(&json).unwrap_or(None),
                     _ => None,
                 },
+                version: row.get(18)?,
+                parent: row.get(19)?,
+                encrypt_fragment: row.get(20)?,
+                owner: row.get(21)?,
+                manage_token_hash: row.get(22)?,
+                shares: match row.get::<_, Option<String>>(23) {
+                    Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_default(),
+                    _ => Vec::new(),
+                },
+                allowed_networks: match row.get::<_, Option<String>>(24) {
+                    Ok(Some(json)) => serde_json::from_str(&json).unwrap_or_default(),
+                    _ => Vec::new(),
+                },
+                require_login: row.get(25)?,
             })
         })
         .expect("Failed to select Pastas from SQLite database.");
@@ -181,38 +180,12 @@ pub fn select_all_from_db() -> Vec<Pasta> {
 }
 
 pub fn insert(pasta: &Pasta) {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+    let conn = open_connection();
 
-    conn.execute(
-        "
-        CREATE TABLE IF NOT EXISTS pasta (
-            id INTEGER PRIMARY KEY,
-            content TEXT NOT NULL,
-            file_name TEXT,
-            file_size INTEGER,
-            extension TEXT NOT NULL,
-            read_only INTEGER NOT NULL,
-            private INTEGER NOT NULL,
-            editable INTEGER NOT NULL,
-            encrypt_server INTEGER NOT NULL,
-            encrypt_client INTEGER NOT NULL,
-            encrypted_key TEXT,
-            created INTEGER NOT NULL,
-            expiration INTEGER NOT NULL,
-            last_read INTEGER NOT NULL,
-            read_count INTEGER NOT NULL,
-            burn_after_reads INTEGER NOT NULL,
-            attachments TEXT,
-            pasta_type TEXT NOT NULL
-        );",
-        params![],
-    )
-    .expect("Failed to create SQLite table for Pasta!");
-
-    // Migration: Add attachments column if it doesn't exist
-    let _ = conn.execute("ALTER TABLE pasta ADD COLUMN attachments TEXT", params![]);
+    insert_with(&conn, pasta);
+}
 
+fn insert_with(conn: &Connection, pasta: &Pasta) {
     conn.execute(
         "INSERT INTO pasta (
                 id,
@@ -232,8 +205,16 @@ pub fn insert(pasta: &Pasta) {
                 read_count,
                 burn_after_reads,
                 pasta_type,
-                attachments
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+                attachments,
+                version,
+                parent,
+                encrypt_fragment,
+                owner,
+                manage_token_hash,
+                shares,
+                allowed_networks,
+                require_login
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26)",
         params![
             pasta.id,
             pasta.content,
@@ -253,14 +234,21 @@ pub fn insert(pasta: &Pasta) {
             pasta.burn_after_reads,
             pasta.pasta_type,
             serde_json::to_string(&pasta.attachments).unwrap_or("".to_string()),
+            pasta.version,
+            pasta.parent,
+            pasta.encrypt_fragment as i32,
+            pasta.owner,
+            pasta.manage_token_hash,
+            serde_json::to_string(&pasta.shares).unwrap_or("[]".to_string()),
+            serde_json::to_string(&pasta.allowed_networks).unwrap_or("[]".to_string()),
+            pasta.require_login as i32,
         ],
     )
     .expect("Failed to insert pasta.");
 }
 
 pub fn update(pasta: &Pasta) {
-    let conn = Connection::open(format!("{}/database.sqlite", ARGS.data_dir))
-        .expect("Failed to open SQLite database!");
+    let conn = open_connection();
 
     conn.execute(
         "UPDATE pasta SET
@@ -280,7 +268,15 @@ pub fn update(pasta: &Pasta) {
             read_count = ?15,
             burn_after_reads = ?16,
             pasta_type = ?17,
-            attachments = ?18
+            attachments = ?18,
+            version = ?19,
+            parent = ?20,
+            encrypt_fragment = ?21,
+            owner = ?22,
+            manage_token_hash = ?23,
+            shares = ?24,
+            allowed_networks = ?25,
+            require_login = ?26
         WHERE id = ?1;",
         params![
             pasta.id,
@@ -301,6 +297,14 @@ pub fn update(pasta: &Pasta) {
             pasta.burn_after_reads,
             pasta.pasta_type,
             serde_json::to_string(&pasta.attachments).unwrap_or("".to_string()),
+            pasta.version,
+            pasta.parent,
+            pasta.encrypt_fragment as i32,
+            pasta.owner,
+            pasta.manage_token_hash,
+            serde_json::to_string(&pasta.shares).unwrap_or("[]".to_string()),
+            serde_json::to_string(&pasta.allowed_networks).unwrap_or("[]".to_string()),
+            pasta.require_login as i32,
         ],
     )
     .expect("Failed to update pasta.");
@@ -311,7 +315,7 @@ pub fn delete_by_id(id: u64) {
         .expect("Failed to open SQLite database!");
 
     conn.execute(
-        "DELETE FROM pasta 
+        "DELETE FROM pasta
         WHERE id = ?1;",
         params![id],
     )
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+
+use crate::args::ARGS;
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+const MONTH: i64 = 30 * DAY;
+const YEAR: i64 = 365 * DAY;
+
+// used whenever an expiry setting cannot be understood
+const FALLBACK: i64 = WEEK;
+
+pub struct ExpiryPreset {
+    pub value: String,
+    pub label: String,
+}
+
+// the names offered by the expiration dropdown before durations were accepted
+fn legacy_duration(name: &str) -> Option<i64> {
+    match name {
+        "1min" => Some(MINUTE),
+        "10min" => Some(10 * MINUTE),
+        "1hour" => Some(HOUR),
+        "24hour" => Some(DAY),
+        "3days" => Some(3 * DAY),
+        "1week" => Some(WEEK),
+        "1month" => Some(MONTH),
+        "6months" => Some(6 * MONTH),
+        "1year" => Some(YEAR),
+        "2years" => Some(2 * YEAR),
+        "4years" => Some(4 * YEAR),
+        "8years" => Some(8 * YEAR),
+        "16years" => Some(16 * YEAR),
+        _ => None,
+    }
+}
+
+fn unit_seconds(unit: &str) -> Option<i64> {
+    match unit {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(MINUTE),
+        "h" | "hour" | "hours" => Some(HOUR),
+        "d" | "day" | "days" => Some(DAY),
+        "w" | "week" | "weeks" => Some(WEEK),
+        "mo" | "month" | "months" => Some(MONTH),
+        "y" | "year" | "years" => Some(YEAR),
+        _ => None,
+    }
+}
+
+/// Parses a duration such as `90m`, `36h`, `10d` or `1d12h` into seconds.
+/// The names of the old expiration dropdown (`24hour`, `6months`, ...) are accepted too.
+pub fn parse_duration(input: &str) -> Option<i64> {
+    let input = input.trim().to_lowercase();
+    if let Some(seconds) = legacy_duration(&input) {
+        return Some(seconds);
+    }
+
+    let mut total: i64 = 0;
+    let mut rest = input.as_str();
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return None;
+        }
+        let amount: i64 = rest[..digits].parse().ok()?;
+        rest = rest[digits..].trim_start();
+
+        let letters = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let unit = unit_seconds(&rest[..letters])?;
+        rest = rest[letters..].trim_start();
+
+        total = total.checked_add(amount.checked_mul(unit)?)?;
+    }
+
+    if total > 0 {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+// accepts RFC 3339 timestamps, and dates without an offset in the server's time zone
+fn parse_timestamp(input: &str) -> Option<i64> {
+    let input = input.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(input) {
+        return Some(date.timestamp());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(input, format) {
+            return Local.from_local_datetime(&date).earliest().map(|d| d.timestamp());
+        }
+    }
+    None
+}
+
+/// The longest allowed lifetime in seconds, None if uploads may never expire.
+pub fn max_expiry() -> Option<i64> {
+    if ARGS.max_expiry.trim() == "never" {
+        return None;
+    }
+    Some(parse_duration(&ARGS.max_expiry).unwrap_or_else(|| {
+        log::error!("Invalid max expiry {:?}, using 1 week", ARGS.max_expiry);
+        FALLBACK
+    }))
+}
+
+/// Converts an expiry given as duration, timestamp or `never` into the expiration
+/// timestamp of a pasta (0 meaning it never expires), enforcing `--max-expiry`.
+pub fn expiration_to_timestamp(expiration: &str, timenow: i64) -> Result<i64, &'static str> {
+    let max = max_expiry();
+
+    if expiration.trim() == "never" {
+        if max.is_some() {
+            return Err("Expiration exceeds maximum allowed");
+        }
+        return Ok(if ARGS.eternal_pasta { 0 } else { timenow + FALLBACK });
+    }
+
+    let expiration = match parse_duration(expiration) {
+        Some(seconds) => timenow.saturating_add(seconds),
+        None => match parse_timestamp(expiration) {
+            Some(timestamp) if timestamp > timenow => timestamp,
+            Some(_) => return Err("Expiration is in the past"),
+            None => return Err("Invalid expiration"),
+        },
+    };
+
+    match max {
+        Some(max) if expiration > timenow.saturating_add(max) => {
+            Err("Expiration exceeds maximum allowed")
+        }
+        _ => Ok(expiration),
+    }
+}
+
+/// The expiration of uploads that did not ask for a specific one.
+pub fn default_expiration(timenow: i64) -> i64 {
+    expiration_to_timestamp(&ARGS.default_expiry, timenow).unwrap_or_else(|e| {
+        log::error!("Default expiry {:?} rejected: {}", ARGS.default_expiry, e);
+        timenow + max_expiry().unwrap_or(FALLBACK).min(FALLBACK)
+    })
+}
+
+pub fn describe_duration(seconds: i64) -> String {
+    let units = [
+        (YEAR, "year"),
+        (MONTH, "month"),
+        (WEEK, "week"),
+        (DAY, "day"),
+        (HOUR, "hour"),
+        (MINUTE, "minute"),
+    ];
+    let (unit, name) = units
+        .iter()
+        .find(|(unit, _)| seconds % unit == 0)
+        .copied()
+        .unwrap_or((1, "second"));
+    let amount = seconds / unit;
+    if amount == 1 {
+        format!("1 {}", name)
+    } else {
+        format!("{} {}s", amount, name)
+    }
+}
+
+/// The dropdown presets configured with `--expiry-presets` that fit within `--max-expiry`.
+pub fn expiry_presets() -> Vec<ExpiryPreset> {
+    let max = max_expiry();
+    ARGS.expiry_presets
+        .iter()
+        .filter_map(|preset| {
+            let seconds = parse_duration(preset).or_else(|| {
+                log::warn!("Ignoring invalid expiry preset {:?}", preset);
+                None
+            })?;
+            if max.is_some_and(|max| seconds > max) {
+                return None;
+            }
+            Some(ExpiryPreset {
+                value: preset.trim().to_string(),
+                label: describe_duration(seconds),
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90m"), Some(90 * MINUTE));
+    assert_eq!(parse_duration("36h"), Some(36 * HOUR));
+    assert_eq!(parse_duration("10d"), Some(10 * DAY));
+    assert_eq!(parse_duration("1d 12h"), Some(DAY + 12 * HOUR));
+    assert_eq!(parse_duration("24hour"), Some(DAY));
+    assert_eq!(parse_duration("6months"), Some(6 * MONTH));
+    assert_eq!(parse_duration("0m"), None);
+    assert_eq!(parse_duration("h"), None);
+    assert_eq!(parse_duration("10 parsecs"), None);
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("2030-01-01T00:00:00Z"), Some(1893456000));
+    assert_eq!(parse_timestamp("2030-01-01T02:00:00+02:00"), Some(1893456000));
+    assert!(parse_timestamp("2030-01-01T00:00").is_some());
+    assert_eq!(parse_timestamp("tomorrow"), None);
+}
+
+#[test]
+fn test_describe_duration() {
+    assert_eq!(describe_duration(MINUTE), "1 minute");
+    assert_eq!(describe_duration(90 * MINUTE), "90 minutes");
+    assert_eq!(describe_duration(DAY), "1 day");
+    assert_eq!(describe_duration(2 * YEAR), "2 years");
+}
@@ -0,0 +1,180 @@
+//! Serving uploaded files from a separate origin. When `--files-origin` is set, the file
+//! and archive endpoints of the main origin redirect to `/f/{token}` on the files origin,
+//! where an uploaded HTML or SVG file runs without access to the cookies of MicroBin.
+//!
+//! The token is sealed with a key that only lives in memory, so it names the file and,
+//! for files encrypted on the server, carries the password without exposing it in the
+//! URL. Tokens expire after a few minutes and when MicroBin restarts.
+
+use crate::args::ARGS;
+use crate::util::misc::now;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+// long enough for a video to be watched and seeked in, short enough that a leaked link
+// is of little use
+const TOKEN_SECONDS: i64 = 10 * 60;
+const NONCE_LEN: usize = 24;
+
+lazy_static! {
+    static ref CIPHER: XChaCha20Poly1305 = XChaCha20Poly1305::new(&XChaCha20Poly1305::generate_key(&mut OsRng));
+}
+
+/// What a token gives access to.
+#[derive(Serialize, Deserialize)]
+pub struct FileGrant {
+    pub id: String,
+    pub fname: Option<String>,
+    pub preview: bool,
+    // the password of a file encrypted on the server
+    pub password: Option<String>,
+    pub archive: bool,
+    expires: i64,
+}
+
+impl FileGrant {
+    pub fn file(id: &str, fname: Option<&String>, preview: bool, password: Option<String>) -> Self {
+        FileGrant {
+            id: id.to_string(),
+            fname: fname.cloned(),
+            preview,
+            password,
+            archive: false,
+            expires: now() + TOKEN_SECONDS,
+        }
+    }
+
+    pub fn archive(id: &str) -> Self {
+        FileGrant {
+            id: id.to_string(),
+            fname: None,
+            preview: false,
+            password: None,
+            archive: true,
+            expires: now() + TOKEN_SECONDS,
+        }
+    }
+
+    fn seal(&self) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let json = serde_json::to_vec(self).expect("grants always serialize");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(CIPHER.encrypt(&nonce, json.as_slice()).expect("encryption cannot fail"));
+        URL_SAFE_NO_PAD.encode(sealed)
+    }
+
+    /// Opens a token, unless it was tampered with or has expired.
+    pub fn open(token: &str) -> Option<Self> {
+        let sealed = URL_SAFE_NO_PAD.decode(token).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let json = CIPHER.decrypt(XNonce::from_slice(nonce), ciphertext).ok()?;
+        let grant: FileGrant = serde_json::from_slice(&json).ok()?;
+        (grant.expires > now()).then_some(grant)
+    }
+
+    /// Sends the browser to the files origin. See Other, so a form posting a password
+    /// is followed up with a plain GET.
+    pub fn redirect(&self) -> HttpResponse {
+        HttpResponse::SeeOther()
+            .append_header(("Location", format!("{}/f/{}", files_origin(), self.seal())))
+            .finish()
+    }
+}
+
+fn files_origin() -> String {
+    ARGS.files_origin
+        .as_ref()
+        .map(|origin| origin.to_string())
+        .unwrap_or_default()
+}
+
+fn host_of(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or_default()
+}
+
+/// Whether the request reached MicroBin through the files origin.
+pub fn is_files_origin(req: &HttpRequest) -> bool {
+    match &ARGS.files_origin {
+        Some(origin) => host_of(&origin.0).eq_ignore_ascii_case(req.connection_info().host()),
+        None => false,
+    }
+}
+
+/// Whether the files of this request should be sent to the files origin instead.
+pub fn should_redirect(req: &HttpRequest) -> bool {
+    ARGS.files_origin.is_some() && !is_files_origin(req)
+}
+
+/// Answers nothing but the file links on the files origin, so no page of MicroBin, and
+/// none of its forms, can be reached through the origin the uploaded files run in.
+pub async fn only_files(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    if is_files_origin(req.request()) && !req.path().starts_with("/f/") {
+        return Ok(req
+            .into_response(HttpResponse::NotFound().finish())
+            .map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Content types a browser would run scripts in when showing them inline. The main
+/// origin only ever offers these as downloads.
+pub fn is_active_content(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    matches!(
+        essence.as_str(),
+        "text/html"
+            | "application/xhtml+xml"
+            | "image/svg+xml"
+            | "text/xml"
+            | "application/xml"
+            | "text/javascript"
+            | "application/javascript"
+            | "application/ecmascript"
+            | "application/pdf"
+    )
+}
+
+#[test]
+fn test_file_grant_tokens() {
+    let token = FileGrant::file("ape-swan", None, true, Some(String::from("secret"))).seal();
+    let grant = FileGrant::open(&token).unwrap();
+    assert_eq!(grant.id, "ape-swan");
+    assert_eq!(grant.password.as_deref(), Some("secret"));
+    assert!(!token.contains("secret"));
+
+    let mut tampered = URL_SAFE_NO_PAD.decode(&token).unwrap();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(FileGrant::open(&URL_SAFE_NO_PAD.encode(tampered)).is_none());
+
+    let mut expired = FileGrant::archive("ape-swan");
+    expired.expires = now() - 1;
+    assert!(FileGrant::open(&expired.seal()).is_none());
+}
+
+#[test]
+fn test_active_content() {
+    assert_eq!(host_of("http://example.com:8443/microbin"), "example.com:8443");
+    assert!(is_active_content("image/svg+xml"));
+    assert!(is_active_content("text/html; charset=utf-8"));
+    assert!(!is_active_content("image/png"));
+}
@@ -0,0 +1,171 @@
+//! The sockets MicroBin accepts connections on: sockets passed by systemd socket
+//! activation, or else every bind address at the port and a Unix socket besides them.
+
+use crate::args::ARGS;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+// the same as actix-web's
+const BACKLOG: i32 = 1024;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => {
+                let scheme = if crate::util::tls::is_enabled() {
+                    "https"
+                } else {
+                    "http"
+                };
+                match listener.local_addr() {
+                    Ok(addr) => write!(f, "{}://{}", scheme, addr),
+                    Err(_) => write!(f, "{}://unknown", scheme),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+            {
+                Some(path) => write!(f, "unix:{}", path),
+                None => write!(f, "unix socket"),
+            },
+        }
+    }
+}
+
+/// The permissions of the Unix socket, from their octal notation.
+fn unix_socket_mode() -> Option<u32> {
+    u32::from_str_radix(&ARGS.unix_socket_mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+}
+
+/// Stops MicroBin if the listeners are configured in a way it cannot follow.
+pub fn check_config() {
+    if unix_socket_mode().is_none() {
+        panic!(
+            "MICROBIN_UNIX_SOCKET_MODE must be octal permissions such as 660, not {}.",
+            ARGS.unix_socket_mode
+        );
+    }
+    if ARGS.bind.is_empty() {
+        panic!("MICROBIN_BIND names no addresses.");
+    }
+    if ARGS.unix_socket_only && ARGS.unix_socket.is_none() {
+        panic!("MICROBIN_UNIX_SOCKET_ONLY is set, but MICROBIN_UNIX_SOCKET names no socket.");
+    }
+    #[cfg(not(unix))]
+    if ARGS.unix_socket.is_some() {
+        panic!("MICROBIN_UNIX_SOCKET is only supported on Unix systems.");
+    }
+}
+
+/// A TCP listener on the address. IPv6 sockets take IPv6 connections only when IPv4
+/// addresses are bound too, which would collide with them otherwise.
+pub fn tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() && ARGS.bind.iter().any(|bind| bind.is_ipv4()) {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn unix(path: &str) -> io::Result<UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // a socket left behind by a previous run, nothing else is removed
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    // a restrictive umask while binding, so nobody can connect before the socket has
+    // its permissions; the umask belongs to the whole process and is restored right away
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    fs::set_permissions(
+        path,
+        fs::Permissions::from_mode(unix_socket_mode().unwrap_or(0o660)),
+    )?;
+    Ok(listener)
+}
+
+/// The sockets systemd passed to MicroBin, see sd_listen_fds(3).
+#[cfg(unix)]
+fn systemd() -> io::Result<Vec<Listener>> {
+    use std::os::unix::io::FromRawFd;
+
+    // the first descriptor systemd passes
+    const LISTEN_FDS_START: i32 = 3;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !for_us || count <= 0 {
+        return Ok(Vec::new());
+    }
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd hands these descriptors over to this process, nothing else owns them
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+            Ok(if socket.local_addr()?.domain() == Domain::UNIX {
+                Listener::Unix(socket.into())
+            } else {
+                Listener::Tcp(socket.into())
+            })
+        })
+        .collect()
+}
+
+/// The listeners to serve MicroBin on.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+
+    #[cfg(unix)]
+    {
+        let activated = systemd()?;
+        if !activated.is_empty() {
+            return Ok(activated);
+        }
+
+        if let Some(path) = &ARGS.unix_socket {
+            listeners.push(Listener::Unix(unix(path)?));
+        }
+    }
+
+    if !ARGS.unix_socket_only {
+        for bind in &ARGS.bind {
+            listeners.push(Listener::Tcp(tcp(SocketAddr::new(*bind, ARGS.port))?));
+        }
+    }
+    Ok(listeners)
+}
@@ -0,0 +1,71 @@
+//! Management links. Every upload gets a secret token when it is created, and the link
+//! `/manage/{id}/{token}` lets whoever holds it edit, extend and remove the upload and
+//! see how often it was read, without an account or the password of the upload. Only a
+//! hash of the token is stored, so the database does not give the links away.
+//!
+//! The link is shown once, on the upload page the uploader is sent to after creating it.
+
+use crate::args::ARGS;
+use crate::pasta::Pasta;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+const FLASH_COOKIE: &str = "microbin_manage_link";
+// long enough to get past the password prompt of a private upload
+const FLASH_SECONDS: i64 = 10 * 60;
+
+pub fn hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A new token and the hash to store with the upload.
+pub fn new_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let hash = hash(&token);
+    (token, hash)
+}
+
+/// Whether the token is the one of the upload. Uploads created before management links
+/// existed have none.
+pub fn token_matches(pasta: &Pasta, token: &str) -> bool {
+    match &pasta.manage_token_hash {
+        Some(stored) if !token.is_empty() => hash(token).as_bytes().ct_eq(stored.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+pub fn url(slug: &str, token: &str) -> String {
+    format!("{}/manage/{}/{}", ARGS.public_path_as_str(), slug, token)
+}
+
+/// The cookie that has the upload page show the management link to the uploader.
+pub fn flash_cookie(slug: &str, token: &str) -> Cookie<'static> {
+    Cookie::build(FLASH_COOKIE, format!("{}.{}", slug, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(ARGS.public_path_as_str().starts_with("https://"))
+        .max_age(Duration::seconds(FLASH_SECONDS))
+        .finish()
+}
+
+/// The management link to show on the page of the upload, if the uploader just created
+/// it. The link is only shown once; see `flash_removal_cookie`.
+pub fn flashed_url(req: &HttpRequest, pasta: &Pasta) -> Option<String> {
+    let cookie = req.cookie(FLASH_COOKIE)?;
+    let (slug, token) = cookie.value().split_once('.')?;
+    (slug == pasta.id_as_animals() && token_matches(pasta, token)).then(|| url(slug, token))
+}
+
+pub fn flash_removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(FLASH_COOKIE, "").path("/").finish();
+    cookie.set_max_age(Duration::ZERO);
+    cookie
+}
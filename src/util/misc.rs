@@ -1,16 +1,27 @@
 use crate::args::ARGS;
 use linkify::{LinkFinder, LinkKind};
-use magic_crypt::{new_magic_crypt, MagicCryptTrait};
 use qrcode_generator::QrCodeEcc;
 use std::fs::{self, File};
-use std::io::{BufReader, Read, Write};
-
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::MutexGuard;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::util::crypto::{self, CryptoError, StreamDecryptor};
 use crate::Pasta;
 
 use super::db::delete;
 
+/// The current unix time in seconds.
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs() as i64,
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    }
+}
+
 pub fn remove_expired(pastas: &mut Vec<Pasta>) {
     // get current time - this will be needed to check which pastas have expired
     let timenow: i64 = match SystemTime::now().duration_since(UNIX_EPOCH) {
@@ -97,6 +108,14 @@ pub fn remove_expired(pastas: &mut Vec<Pasta>) {
     }
 }
 
+/// A copy of the pasta at the index, with the lock on the pastas released. Checking
+/// passwords and decrypting is slow on purpose, and other requests should not wait
+/// for it. Whatever is done with the copy can only be stored after the lock is taken
+/// again and the pasta is found unchanged.
+pub fn unlocked_copy(pastas: MutexGuard<Vec<Pasta>>, index: usize) -> Pasta {
+    pastas[index].clone()
+}
+
 pub fn string_to_qr_svg(str: &str) -> String {
     qrcode_generator::to_svg_to_string(str, QrCodeEcc::Low, 256, None::<&str>).unwrap()
 }
@@ -112,43 +131,27 @@ pub fn encrypt(text_str: &str, key_str: &str) -> String {
         return String::from("");
     }
 
-    let mc = new_magic_crypt!(key_str, 256);
-
-    mc.encrypt_str_to_base64(text_str)
+    crypto::seal_text(text_str, key_str).expect("Failed to encrypt text")
 }
 
-pub fn decrypt(text_str: &str, key_str: &str) -> Result<String, magic_crypt::MagicCryptError> {
+pub fn decrypt(text_str: &str, key_str: &str) -> Result<String, CryptoError> {
     if text_str.is_empty() {
         return Ok(String::from(""));
     }
 
-    let mc = new_magic_crypt!(key_str, 256);
-
-    mc.decrypt_base64_to_string(text_str)
+    crypto::open_text(text_str, key_str)
 }
 
 pub fn encrypt_file(
     passphrase: &str,
     input_file_path: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Read the input file into memory
+    // Encrypt chunk by chunk into a new file with the .enc extension
     let file = File::open(input_file_path).expect("Tried to encrypt non-existent file");
-    let mut reader = BufReader::new(file);
-    let mut input_data = Vec::new();
-    reader.read_to_end(&mut input_data)?;
-
-    // Create a MagicCrypt instance with the given passphrase
-    let mc = new_magic_crypt!(passphrase, 256);
-
-    // Encrypt the input data
-    let ciphertext = mc.encrypt_bytes_to_bytes(&input_data[..]);
-
-    // Write the encrypted data to a new file with the .enc extension
-    let mut f = File::create(format!("{}.enc", input_file_path))?;
-    f.write_all(ciphertext.as_slice())?;
+    let output = File::create(format!("{}.enc", input_file_path))?;
+    crypto::seal_stream(BufReader::new(file), BufWriter::new(output), passphrase)?;
 
     // Delete the original input file
-    // input_file.seek(SeekFrom::Start(0))?;
     fs::remove_file(input_file_path)?;
 
     Ok(())
@@ -163,14 +166,30 @@ pub fn decrypt_file(
     let mut ciphertext = Vec::new();
     reader.read_to_end(&mut ciphertext)?;
 
-    // Create a MagicCrypt instance with the given passphrase
-    let mc = new_magic_crypt!(passphrase, 256);
-    // Encrypt the input data
-    let res = mc.decrypt_bytes_to_bytes(&ciphertext[..]);
+    // Files written before the versioned format are decrypted with magic_crypt
+    crypto::open(&ciphertext, passphrase).map_err(|e| e.into())
+}
 
-    if res.is_err() {
-        return Err("Failed to decrypt file".into());
+/// Decrypts a file into `writer` one chunk at a time. Files from older versions are
+/// sealed in one piece and still have to be decrypted in memory.
+pub fn decrypt_file_into(
+    passphrase: &str,
+    input_file_path: &str,
+    writer: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::open(input_file_path)?;
+    match StreamDecryptor::new(&mut file, passphrase)? {
+        Some(decryptor) => {
+            let len = decryptor.plaintext_len();
+            let mut position = 0;
+            while position < len {
+                let chunk = decryptor.read_at(&mut file, position, len)?;
+                position += chunk.len() as u64;
+                writer.write_all(&chunk)?;
+            }
+        }
+        None => writer.write_all(&decrypt_file(passphrase, &file)?)?,
     }
-
-    Ok(res.unwrap())
+    writer.flush()?;
+    Ok(())
 }
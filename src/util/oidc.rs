@@ -0,0 +1,541 @@
+//! OpenID Connect single sign-on. When an issuer is configured, the protected pages
+//! require a session, which is started by signing in with the identity provider through
+//! the authorization code flow with PKCE.
+//!
+//! The ID token is received directly from the token endpoint of the issuer, so its
+//! contents are trusted without checking its signature, as OpenID Connect Core 3.1.3.7
+//! allows; its issuer, audience, expiry and nonce are still checked.
+
+use crate::args::ARGS;
+use crate::util::http_client;
+use crate::util::misc::now;
+use crate::AppState;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const STATE_COOKIE: &str = "microbin_oidc_state";
+
+// time to sign in at the identity provider
+const LOGIN_SECONDS: i64 = 10 * 60;
+// sign ins that can be underway at once; anyone can start one, so there is a limit
+const MAX_PENDING: usize = 1000;
+
+pub type Claims = Map<String, Value>;
+
+#[derive(Deserialize, Clone)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: Option<String>,
+}
+
+// a sign in started at the identity provider, by its state parameter
+struct PendingLogin {
+    nonce: String,
+    verifier: String,
+    next: String,
+    started: i64,
+}
+
+// adds a sign in, forgetting those that expired, and the oldest one when there are
+// too many underway
+fn remember(pending: &mut HashMap<String, PendingLogin>, state: String, login: PendingLogin) {
+    pending.retain(|_, other| other.started + LOGIN_SECONDS > login.started);
+    if pending.len() >= MAX_PENDING {
+        let oldest = pending
+            .iter()
+            .min_by_key(|(_, other)| other.started)
+            .map(|(state, _)| state.clone());
+        if let Some(oldest) = oldest {
+            pending.remove(&oldest);
+        }
+    }
+    pending.insert(state, login);
+}
+
+#[derive(Default)]
+pub struct Oidc {
+    // fetched from the discovery document on the first sign in
+    provider: Mutex<Option<Provider>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+pub fn is_enabled() -> bool {
+    ARGS.oidc_issuer.is_some()
+}
+
+/// Stops MicroBin if single sign-on is configured only partly.
+pub fn check_config() {
+    if let Some(issuer) = &ARGS.oidc_issuer {
+        if ARGS.oidc_client_id.as_deref().unwrap_or_default().trim().is_empty() {
+            panic!("MICROBIN_OIDC_ISSUER is set, but MICROBIN_OIDC_CLIENT_ID is not.");
+        }
+        if !issuer.0.starts_with("https://") {
+            log::warn!(
+                "The OIDC issuer {} does not use HTTPS. ID tokens from it cannot be trusted outside of testing.",
+                issuer
+            );
+        }
+    }
+}
+
+/// Where the identity provider sends the browser back to.
+fn redirect_uri(req: &HttpRequest) -> String {
+    let base = match &ARGS.public_path {
+        Some(public_path) => public_path.to_string(),
+        None => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    };
+    format!("{}/oidc/callback", base)
+}
+
+/// Only paths of this MicroBin are followed after signing in.
+pub fn safe_next(next: Option<&str>) -> String {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') => {
+            next.to_string()
+        }
+        _ => String::from("/"),
+    }
+}
+
+fn decode_id_token(id_token: &str) -> Option<Claims> {
+    let payload = id_token.split('.').nth(1)?;
+    let json = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+fn validate_id_token(
+    claims: &Claims,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<(), &'static str> {
+    if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+        return Err("the ID token was issued by someone else");
+    }
+
+    let audience: Vec<&str> = match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.as_str()],
+        Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !audience.contains(&client_id) {
+        return Err("the ID token is meant for another client");
+    }
+    if audience.len() > 1 && claims.get("azp").and_then(Value::as_str) != Some(client_id) {
+        return Err("the ID token is meant for another client");
+    }
+
+    if claims.get("exp").and_then(Value::as_i64).unwrap_or(0) <= now {
+        return Err("the ID token has expired");
+    }
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err("the ID token belongs to another sign in");
+    }
+    if claims.get("sub").and_then(Value::as_str).is_none() {
+        return Err("the ID token names nobody");
+    }
+
+    Ok(())
+}
+
+/// Whether the claim is the value, or a list that contains it.
+fn claim_contains(claims: &Claims, claim: &str, value: &str) -> bool {
+    match claims.get(claim) {
+        Some(Value::String(s)) => s == value,
+        Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(value)),
+        _ => false,
+    }
+}
+
+/// Whether the signed in person gets admin rights.
+pub fn is_admin(claims: &Claims) -> bool {
+    match &ARGS.oidc_admin_value {
+        Some(value) => claim_contains(claims, &ARGS.oidc_admin_claim, value),
+        None => false,
+    }
+}
+
+/// The subject and the name to suggest for the account of the signed in person.
+pub fn identity(claims: &Claims) -> (String, String) {
+    let subject = claims
+        .get("sub")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let name = ["preferred_username", "email", "name"]
+        .iter()
+        .find_map(|claim| claims.get(*claim).and_then(Value::as_str))
+        .unwrap_or(&subject)
+        .to_string();
+    (subject, name)
+}
+
+impl Oidc {
+    async fn provider(&self) -> Result<Provider, String> {
+        if let Some(provider) = self.provider.lock().unwrap().as_ref() {
+            return Ok(provider.clone());
+        }
+
+        let issuer = ARGS.oidc_issuer.as_ref().map(|issuer| issuer.to_string()).unwrap_or_default();
+        let provider: Provider = http_client::new_async()
+            .get(format!("{}/.well-known/openid-configuration", issuer))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Could not reach the identity provider: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid discovery document: {}", e))?;
+
+        if provider.issuer.trim_end_matches('/') != issuer {
+            return Err(format!(
+                "The discovery document names the issuer {} instead of {}",
+                provider.issuer, issuer
+            ));
+        }
+
+        *self.provider.lock().unwrap() = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Starts a sign in, returning the URL of the identity provider to send the
+    /// browser to and the cookie that ties the sign in to the browser.
+    pub async fn start(&self, req: &HttpRequest, next: String) -> Result<(String, Cookie<'static>), String> {
+        let provider = self.provider().await?;
+
+        let state = random_string();
+        let nonce = random_string();
+        let verifier = random_string();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let url = format!(
+            "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+            provider.authorization_endpoint,
+            if provider.authorization_endpoint.contains('?') { '&' } else { '?' },
+            encode(ARGS.oidc_client_id.as_deref().unwrap_or_default()),
+            encode(&redirect_uri(req)),
+            encode(&ARGS.oidc_scopes),
+            state,
+            nonce,
+            challenge,
+        );
+
+        remember(
+            &mut self.pending.lock().unwrap(),
+            state.clone(),
+            PendingLogin {
+                nonce,
+                verifier,
+                next,
+                started: now(),
+            },
+        );
+
+        let cookie = Cookie::build(STATE_COOKIE, state)
+            .path("/")
+            .http_only(true)
+            // sent along when the identity provider sends the browser back
+            .same_site(SameSite::Lax)
+            .secure(ARGS.public_path_as_str().starts_with("https://"))
+            .max_age(Duration::seconds(LOGIN_SECONDS))
+            .finish();
+
+        Ok((url, cookie))
+    }
+
+    /// Finishes a sign in when the identity provider sends the browser back, returning
+    /// the claims about the person and the path to continue to.
+    pub async fn finish(&self, req: &HttpRequest, code: &str, state: &str) -> Result<(Claims, String), String> {
+        if req.cookie(STATE_COOKIE).map(|cookie| cookie.value().to_string()).as_deref() != Some(state) {
+            return Err(String::from("The sign in was started in another browser."));
+        }
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started + LOGIN_SECONDS > now())
+            .ok_or_else(|| String::from("The sign in has expired."))?;
+
+        let provider = self.provider().await?;
+        let client_id = ARGS.oidc_client_id.as_deref().unwrap_or_default();
+        let redirect_uri = redirect_uri(req);
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code_verifier", login.verifier.as_str()),
+        ];
+
+        let client = http_client::new_async();
+        let mut request = client.post(&provider.token_endpoint);
+        match &ARGS.oidc_client_secret {
+            Some(secret) => request = request.basic_auth(client_id, Some(secret.as_str())),
+            None => form.push(("client_id", client_id)),
+        }
+        let tokens: TokenResponse = request
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("The identity provider refused the sign in: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+
+        let mut claims = decode_id_token(&tokens.id_token).ok_or_else(|| String::from("Invalid ID token."))?;
+        validate_id_token(&claims, &provider.issuer, client_id, &login.nonce, now())
+            .map_err(|e| format!("Rejected the sign in: {}.", e))?;
+
+        // groups and the like are often only available from the user info
+        if let (Some(endpoint), Some(access_token)) = (&provider.userinfo_endpoint, &tokens.access_token) {
+            match client.get(endpoint).bearer_auth(access_token).send().await {
+                Ok(response) if response.status().is_success() => {
+                    if let Ok(info) = response.json::<Claims>().await {
+                        if info.get("sub") == claims.get("sub") {
+                            for (claim, value) in info {
+                                claims.entry(claim).or_insert(value);
+                            }
+                        }
+                    }
+                }
+                Ok(response) => log::warn!("The user info endpoint answered with {}.", response.status()),
+                Err(e) => log::warn!("Could not fetch the user info: {}", e),
+            }
+        }
+
+        Ok((claims, login.next))
+    }
+}
+
+/// A cookie that makes the browser forget the sign in it started.
+pub fn state_removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(STATE_COOKIE, "").path("/").finish();
+    cookie.set_max_age(Duration::ZERO);
+    cookie
+}
+
+/// Sends requests without a session to the identity provider. Only pages are
+/// redirected; anything else is refused.
+pub async fn require_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let signed_in = req
+        .app_data::<web::Data<AppState>>()
+        .is_some_and(|data| data.sessions.principal(req.request()).is_some());
+    if signed_in {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err(ErrorUnauthorized("Please sign in first."));
+    }
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .to_string();
+    let response = HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!("{}/oidc/login?next={}", ARGS.public_path_as_str(), encode(&path)),
+        ))
+        .finish();
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[test]
+fn test_id_token_validation() {
+    let claims: Claims = serde_json::from_value(serde_json::json!({
+        "iss": "https://sso.example.com",
+        "aud": ["microbin", "other"],
+        "azp": "microbin",
+        "sub": "1234",
+        "exp": 2000,
+        "nonce": "n-0S6",
+        "groups": ["staff", "microbin-admins"],
+    }))
+    .unwrap();
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+    assert!(decode_id_token(&format!("e30.{}.c2ln", payload)) == Some(claims.clone()));
+
+    assert!(validate_id_token(&claims, "https://sso.example.com", "microbin", "n-0S6", 1000).is_ok());
+    assert!(validate_id_token(&claims, "https://evil.example.com", "microbin", "n-0S6", 1000).is_err());
+    assert!(validate_id_token(&claims, "https://sso.example.com", "other", "n-0S6", 1000).is_err());
+    assert!(validate_id_token(&claims, "https://sso.example.com", "microbin", "other", 1000).is_err());
+    assert!(validate_id_token(&claims, "https://sso.example.com", "microbin", "n-0S6", 2000).is_err());
+
+    assert!(claim_contains(&claims, "groups", "microbin-admins"));
+    assert!(!claim_contains(&claims, "groups", "microbin"));
+    assert!(claim_contains(&claims, "sub", "1234"));
+
+    assert_eq!(safe_next(Some("/admin")), "/admin");
+    assert_eq!(safe_next(Some("//evil.example.com")), "/");
+    assert_eq!(safe_next(Some("https://evil.example.com")), "/");
+}
+
+#[test]
+fn test_pending_logins_are_limited() {
+    let login = |started| PendingLogin {
+        nonce: String::new(),
+        verifier: String::new(),
+        next: String::new(),
+        started,
+    };
+    let mut pending = HashMap::new();
+    remember(&mut pending, String::from("expired"), login(1000));
+    remember(&mut pending, String::from("oldest"), login(1999));
+    for i in 1..MAX_PENDING {
+        remember(&mut pending, i.to_string(), login(2000));
+    }
+    assert!(!pending.contains_key("expired"));
+    assert_eq!(pending.len(), MAX_PENDING);
+
+    remember(&mut pending, String::from("newest"), login(2001));
+    assert_eq!(pending.len(), MAX_PENDING);
+    assert!(!pending.contains_key("oldest"));
+    assert!(pending.contains_key("newest"));
+}
+
+#[actix_web::test]
+async fn test_callback_against_token_endpoint() {
+    use actix_web::{test, App};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // a stub of the identity provider, whose token endpoint answers a single request
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let oidc = Oidc::default();
+    *oidc.provider.lock().unwrap() = Some(Provider {
+        issuer: issuer.clone(),
+        authorization_endpoint: format!("{}/authorize", issuer),
+        token_endpoint: format!("{}/token", issuer),
+        userinfo_endpoint: None,
+    });
+
+    let (url, _) = oidc
+        .start(&test::TestRequest::default().to_http_request(), String::from("/mine"))
+        .await
+        .unwrap();
+    let param = |name: &str| {
+        url.split(['?', '&'])
+            .find_map(|pair| pair.strip_prefix(&format!("{}=", name)))
+            .unwrap()
+            .to_string()
+    };
+    let (state, nonce) = (param("state"), param("nonce"));
+
+    let claims = serde_json::json!({
+        "iss": issuer,
+        "aud": ARGS.oidc_client_id.as_deref().unwrap_or_default(),
+        "sub": "1234",
+        "preferred_username": "alice",
+        "exp": now() + 60,
+        "nonce": nonce,
+    });
+    let id_token = format!("e30.{}.c2ln", URL_SAFE_NO_PAD.encode(claims.to_string()));
+    let stub = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut form = vec![0u8; length];
+        reader.read_exact(&mut form).unwrap();
+
+        let body = serde_json::json!({ "id_token": id_token, "token_type": "Bearer" }).to_string();
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        String::from_utf8(form).unwrap()
+    });
+
+    let data = web::Data::new(AppState {
+        pastas: Mutex::new(Vec::new()),
+        attempts: Default::default(),
+        sessions: Default::default(),
+        two_factor: crate::util::two_factor::TwoFactor::load(),
+        users: crate::util::users::UserStore::load(),
+        oidc,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(data.clone())
+            .service(crate::endpoints::oidc::get_oidc_callback),
+    )
+    .await;
+    let callback = || {
+        test::TestRequest::get()
+            .uri(&format!("/oidc/callback?code=the-code&state={}", state))
+            .cookie(Cookie::new(STATE_COOKIE, state.clone()))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, callback()).await;
+    assert_eq!(res.status(), 200);
+    assert!(res
+        .response()
+        .cookies()
+        .any(|cookie| cookie.name() == crate::util::session::SESSION_COOKIE && !cookie.value().is_empty()));
+    let form = stub.join().unwrap();
+    assert!(form.contains("code=the-code"));
+    assert!(form.contains("code_verifier="));
+    let body = test::read_body(res).await;
+    assert!(String::from_utf8_lossy(&body).contains("/mine"));
+
+    // a sign in can only be finished once
+    let res = test::call_service(&app, callback()).await;
+    assert_eq!(res.status(), 403);
+}
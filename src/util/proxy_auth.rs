@@ -0,0 +1,115 @@
+//! Authentication by a reverse proxy such as oauth2-proxy or Authelia. The proxy signs
+//! people in and names them in a header, which is only believed when the request comes
+//! straight from one of the trusted proxy addresses, or over the Unix socket if that
+//! is trusted; anyone else could send it too.
+
+use crate::args::ARGS;
+use crate::util::session::Principal;
+use crate::AppState;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorUnauthorized;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+pub fn is_enabled() -> bool {
+    ARGS.auth_proxy_header.is_some()
+}
+
+/// Stops MicroBin if the header would be trusted from nowhere.
+pub fn check_config() {
+    if is_enabled() && ARGS.auth_proxy_trusted.is_empty() && !ARGS.trust_unix_socket {
+        panic!("MICROBIN_AUTH_PROXY_HEADER is set, but MICROBIN_AUTH_PROXY_TRUSTED names no proxy addresses and MICROBIN_TRUST_UNIX_SOCKET is off.");
+    }
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[IpNet]) -> bool {
+    let ip = ip.to_canonical();
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Whether a list of groups, separated by commas, contains the group.
+fn groups_contain(groups: &str, group: &str) -> bool {
+    groups.split(',').any(|g| g.trim() == group)
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Who the proxy says sent the request, if it came from a trusted proxy.
+pub fn principal(req: &HttpRequest, data: &AppState) -> Option<Principal> {
+    let username = header(req, ARGS.auth_proxy_header.as_deref()?)?;
+
+    // connections over the Unix socket have no address, and are only trusted on request
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let trusted = match peer {
+        Some(peer) => is_trusted(peer, &ARGS.auth_proxy_trusted),
+        None => ARGS.trust_unix_socket,
+    };
+    if !trusted {
+        log::warn!(
+            "Ignored the {} header of a request from {}, which is not a trusted proxy.",
+            ARGS.auth_proxy_header.as_deref().unwrap_or_default(),
+            peer.map_or(String::from("the Unix socket"), |peer| peer.to_string())
+        );
+        return None;
+    }
+
+    let admin = match (&ARGS.auth_proxy_groups_header, &ARGS.auth_proxy_admin_group) {
+        (Some(groups_header), Some(admin_group)) => {
+            header(req, groups_header).is_some_and(|groups| groups_contain(groups, admin_group))
+        }
+        _ => false,
+    };
+    if admin {
+        return Some(Principal::Admin);
+    }
+
+    let user = data
+        .users
+        .find_or_create_sso(&format!("proxy:{}", username), username);
+    Some(Principal::User(user.id))
+}
+
+/// Authenticates the requests to the protected pages by the headers of the proxy. The
+/// person is known for this one request only; there is no session to sign out of.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let principal = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|data| principal(req.request(), data));
+
+    match principal {
+        Some(principal) => {
+            req.extensions_mut().insert(principal);
+        }
+        // single sign-on can still sign in those the proxy does not name
+        None if !crate::util::oidc::is_enabled() => {
+            return Err(ErrorUnauthorized("Please sign in at the proxy first."));
+        }
+        None => {}
+    }
+
+    next.call(req).await
+}
+
+#[test]
+fn test_trusted_proxies() {
+    let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+    assert!(is_trusted("10.1.2.3".parse().unwrap(), &trusted));
+    assert!(is_trusted("::ffff:10.1.2.3".parse().unwrap(), &trusted));
+    assert!(is_trusted("::1".parse().unwrap(), &trusted));
+    assert!(!is_trusted("192.168.1.1".parse().unwrap(), &trusted));
+
+    assert!(groups_contain("staff, microbin-admins", "microbin-admins"));
+    assert!(!groups_contain("microbin-admins-old", "microbin-admins"));
+}
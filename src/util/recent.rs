@@ -0,0 +1,146 @@
+//! The uploads a browser created, so people without an account can find them again on
+//! the `/mine` page. The list lives in a cookie signed with a key kept in the data
+//! directory: it survives restarts, and a browser cannot make it list uploads it did
+//! not create. Only the newest uploads are kept, so the cookie stays small.
+//!
+//! It also tells the upload page that its viewer created the upload a moment ago, which
+//! then does not count as a read.
+
+use crate::args::ARGS;
+use crate::util::misc::now;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs;
+use std::io::ErrorKind;
+
+const COOKIE_NAME: &str = "microbin_recent";
+const KEY_FILE: &str = "recent_key";
+const MAX_ENTRIES: usize = 50;
+// how long after creating an upload viewing it does not count as a read
+const FRESH_SECONDS: i64 = 15;
+
+lazy_static! {
+    static ref KEY: Vec<u8> = load_key();
+}
+
+/// An upload the browser created.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Recent {
+    pub id: u64,
+    pub created: i64,
+}
+
+fn key_path() -> String {
+    format!("{}/{}", ARGS.data_dir, KEY_FILE)
+}
+
+fn load_key() -> Vec<u8> {
+    match fs::read_to_string(key_path()) {
+        Ok(key) => URL_SAFE_NO_PAD
+            .decode(key.trim())
+            .unwrap_or_else(|e| panic!("Failed to read {}: {}", key_path(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut key = vec![0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            if let Err(e) = fs::write(key_path(), URL_SAFE_NO_PAD.encode(&key)) {
+                log::error!("Failed to write {}, recent uploads will be forgotten on restart: {}", key_path(), e);
+            }
+            key
+        }
+        Err(e) => panic!("Failed to read {}: {}", key_path(), e),
+    }
+}
+
+fn mac(key: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+// "id-created" pairs separated by dots, then the signature
+fn encode(key: &[u8], entries: &[Recent]) -> String {
+    let payload = entries
+        .iter()
+        .map(|entry| format!("{}-{}", entry.id, entry.created))
+        .collect::<Vec<_>>()
+        .join(".");
+    let signature = URL_SAFE_NO_PAD.encode(mac(key, &payload).finalize().into_bytes());
+    format!("{}~{}", payload, signature)
+}
+
+fn decode(key: &[u8], value: &str) -> Option<Vec<Recent>> {
+    let (payload, signature) = value.rsplit_once('~')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    mac(key, payload).verify_slice(&signature).ok()?;
+
+    if payload.is_empty() {
+        return Some(Vec::new());
+    }
+    payload
+        .split('.')
+        .map(|entry| {
+            let (id, created) = entry.split_once('-')?;
+            Some(Recent {
+                id: id.parse().ok()?,
+                created: created.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// The uploads the browser created, newest first.
+pub fn entries(req: &HttpRequest) -> Vec<Recent> {
+    req.cookie(COOKIE_NAME)
+        .and_then(|cookie| decode(&KEY, cookie.value()))
+        .unwrap_or_default()
+}
+
+/// The cookie for a list of uploads.
+pub fn cookie(entries: &[Recent]) -> Cookie<'static> {
+    Cookie::build(COOKIE_NAME, encode(&KEY, entries))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(ARGS.public_path_as_str().starts_with("https://"))
+        .max_age(Duration::days(365))
+        .finish()
+}
+
+/// The cookie that adds a new upload to the list of the browser.
+pub fn cookie_with(req: &HttpRequest, id: u64, created: i64) -> Cookie<'static> {
+    let mut entries = entries(req);
+    entries.retain(|entry| entry.id != id);
+    entries.insert(0, Recent { id, created });
+    entries.truncate(MAX_ENTRIES);
+    cookie(&entries)
+}
+
+/// Whether the browser created the upload a moment ago.
+pub fn is_fresh_upload(req: &HttpRequest, id: u64) -> bool {
+    let now = now();
+    entries(req)
+        .iter()
+        .any(|entry| entry.id == id && entry.created + FRESH_SECONDS > now)
+}
+
+#[test]
+fn test_recent_cookie() {
+    let key = [7u8; 32];
+    let entries = vec![
+        Recent { id: 42, created: 1700000000 },
+        Recent { id: 7, created: 1690000000 },
+    ];
+    let value = encode(&key, &entries);
+    assert_eq!(decode(&key, &value), Some(entries));
+    assert_eq!(decode(&key, &encode(&key, &[])), Some(Vec::new()));
+
+    // another id, or the same list signed with another key
+    assert_eq!(decode(&key, &value.replacen("42", "43", 1)), None);
+    assert_eq!(decode(&[8u8; 32], &value), None);
+}
@@ -0,0 +1,97 @@
+//! Security headers for every response. Pages get a Content-Security-Policy that only
+//! runs scripts served by MicroBin and the inline scripts of the templates, which carry a
+//! nonce that changes with every request. Uploaded files get a sandboxing policy instead,
+//! so an uploaded HTML or SVG file cannot run scripts as MicroBin.
+
+use crate::args::ARGS;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::RngCore;
+
+/// The policy for uploaded content: nothing runs, and the file is treated as coming from
+/// an origin of its own.
+pub const UPLOAD_CSP: &str =
+    "sandbox; default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'";
+
+#[derive(Clone)]
+struct CspNonce(String);
+
+/// The nonce the inline scripts of the page must carry.
+pub fn nonce(req: &HttpRequest) -> String {
+    if let Some(CspNonce(nonce)) = req.extensions().get::<CspNonce>() {
+        return nonce.clone();
+    }
+
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let nonce = STANDARD.encode(bytes);
+    req.extensions_mut().insert(CspNonce(nonce.clone()));
+    nonce
+}
+
+// the origin of a configured URL as a CSP source, with a leading space
+fn origin_of(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let host = rest.split('/').next().unwrap_or_default();
+            format!(" {}://{}", scheme, host)
+        }
+        None => String::new(),
+    }
+}
+
+fn page_csp(nonce: Option<&str>) -> String {
+    // the public path serves the scripts and styles when MicroBin is reached under
+    // another address
+    let origin = origin_of(&ARGS.public_path_as_str());
+    // uploaded files are shown from and downloaded through the files origin
+    let files = ARGS
+        .files_origin
+        .as_ref()
+        .map(|files_origin| origin_of(&files_origin.0))
+        .unwrap_or_default();
+    let frame_ancestors = ARGS.frame_ancestors.as_deref().unwrap_or("'none'");
+    let script_src = match nonce {
+        Some(nonce) => format!("'self'{} 'nonce-{}'", origin, nonce),
+        None => format!("'self'{}", origin),
+    };
+
+    format!(
+        "default-src 'self'{origin}{files}; script-src {script_src}; style-src 'self'{origin} 'unsafe-inline'; \
+         img-src 'self'{origin}{files} data: blob:; media-src 'self'{origin}{files} blob:; object-src 'none'; \
+         base-uri 'none'; form-action 'self'{origin}{files}; frame-ancestors {frame_ancestors}"
+    )
+}
+
+/// Adds the security headers a handler did not set itself.
+pub fn add_headers<B>(res: &mut ServiceResponse<B>) {
+    if ARGS.disable_security_headers {
+        return;
+    }
+
+    let nonce = res
+        .request()
+        .extensions()
+        .get::<CspNonce>()
+        .map(|CspNonce(nonce)| nonce.clone());
+
+    let headers = res.headers_mut();
+    if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        if let Ok(value) = HeaderValue::from_str(&page_csp(nonce.as_deref())) {
+            headers.insert(header::CONTENT_SECURITY_POLICY, value);
+        }
+    }
+    if ARGS.frame_ancestors.is_none() {
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("same-origin"));
+    headers.insert(
+        header::HeaderName::from_static("cross-origin-opener-policy"),
+        HeaderValue::from_static("same-origin"),
+    );
+}
+
@@ -0,0 +1,176 @@
+//! Admin and user sessions. The cookie holds a random session id and an HMAC over it, so
+//! ids cannot be forged and sessions can be ended on the server side. Sessions live in
+//! memory and end when MicroBin restarts.
+
+use crate::args::ARGS;
+use crate::pasta::Pasta;
+use crate::util::misc::now;
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::{HttpMessage, HttpRequest};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const SESSION_COOKIE: &str = "microbin_session";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who a session belongs to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Principal {
+    Admin,
+    User(u64),
+}
+
+struct Session {
+    principal: Principal,
+    last_seen: i64,
+}
+
+pub struct SessionStore {
+    key: [u8; 32],
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        SessionStore {
+            key,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn idle_timeout() -> i64 {
+    ARGS.session_idle_minutes as i64 * 60
+}
+
+impl SessionStore {
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length")
+    }
+
+    /// Returns the session id of a cookie value if its signature is valid.
+    fn verified_id(&self, value: &str) -> Option<String> {
+        let (id, signature) = value.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        Some(id.to_string())
+    }
+
+    /// Starts a new session and returns the cookie that identifies it.
+    pub fn create(&self, principal: Principal) -> Cookie<'static> {
+        let mut id_bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut id_bytes);
+        let id = URL_SAFE_NO_PAD.encode(id_bytes);
+
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        let now = now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.last_seen + idle_timeout() > now);
+        sessions.insert(
+            id.clone(),
+            Session {
+                principal,
+                last_seen: now,
+            },
+        );
+
+        session_cookie(format!("{}.{}", id, signature))
+    }
+
+    /// Who the live session of the request belongs to, if it has one. Counts as
+    /// activity, so the idle timeout starts over.
+    pub fn principal(&self, req: &HttpRequest) -> Option<Principal> {
+        // named by a trusted reverse proxy for this request, see proxy_auth
+        if let Some(principal) = req.extensions().get::<Principal>() {
+            return Some(*principal);
+        }
+
+        let id = req
+            .cookie(SESSION_COOKIE)
+            .and_then(|cookie| self.verified_id(cookie.value()))?;
+
+        let now = now();
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(&id) {
+            Some(session) if session.last_seen + idle_timeout() > now => {
+                session.last_seen = now;
+                Some(session.principal)
+            }
+            Some(_) => {
+                sessions.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn is_admin(&self, req: &HttpRequest) -> bool {
+        self.principal(req) == Some(Principal::Admin)
+    }
+
+    /// The id of the signed in user, if the request comes from one.
+    pub fn user(&self, req: &HttpRequest) -> Option<u64> {
+        match self.principal(req) {
+            Some(Principal::User(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may change or remove the upload without its password: the
+    /// admin may change anything, users the uploads they own.
+    pub fn can_manage(&self, req: &HttpRequest, pasta: &Pasta) -> bool {
+        match self.principal(req) {
+            Some(Principal::Admin) => true,
+            Some(Principal::User(id)) => pasta.owner == Some(id),
+            None => false,
+        }
+    }
+
+    /// Ends the session of the request, if there is one.
+    pub fn destroy(&self, req: &HttpRequest) {
+        if let Some(id) = req
+            .cookie(SESSION_COOKIE)
+            .and_then(|cookie| self.verified_id(cookie.value()))
+        {
+            self.sessions.lock().unwrap().remove(&id);
+        }
+    }
+
+    /// Ends all sessions of a user, after their account was removed or their password
+    /// was changed.
+    pub fn destroy_user(&self, user: u64) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.principal != Principal::User(user));
+    }
+}
+
+fn session_cookie(value: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(ARGS.public_path_as_str().starts_with("https://"))
+        .finish()
+}
+
+/// A cookie that makes the browser forget the session.
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = session_cookie(String::new());
+    cookie.set_max_age(Duration::ZERO);
+    cookie
+}
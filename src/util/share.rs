@@ -0,0 +1,228 @@
+//! Share links. The uploader of a private or read-only upload can hand out links
+//! `/s/{token}` that let others view it, without giving away its password. Every link
+//! can expire and be limited to a number of views, and is revoked by removing it.
+//!
+//! For uploads encrypted on the server, the link carries the password wrapped with its
+//! token: only the hash of the token is stored, so the password can only be recovered
+//! by whoever holds the link. Tokens are random, so the wrapping key is derived from
+//! them with HKDF; stretching it like a password would only slow every view down.
+
+use crate::args::ARGS;
+use crate::pasta::Pasta;
+use crate::util::crypto::CryptoError;
+use crate::util::manage;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// How many share links an upload can have at once.
+pub const MAX_SHARES: usize = 20;
+// files of a link limited to a number of views can still be downloaded for this long
+// after its last view, so the page that was opened keeps working
+const FILE_GRACE_SECONDS: i64 = 60 * 60;
+
+// the format of the wrapped keys, for whatever replaces it
+const WRAPPED_PREFIX: &str = "hk1$";
+const NONCE_LEN: usize = 24;
+
+fn wrapping_cipher(token: &str) -> XChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, token.as_bytes())
+        .expand(b"microbin share link key", &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    XChaCha20Poly1305::new(&key.into())
+}
+
+fn wrap_key(password: &str, token: &str) -> String {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        wrapping_cipher(token)
+            .encrypt(&nonce, password.as_bytes())
+            .expect("encryption cannot fail"),
+    );
+    format!("{}{}", WRAPPED_PREFIX, BASE64.encode(sealed))
+}
+
+fn unwrap_key(wrapped: &str, token: &str) -> Result<String, CryptoError> {
+    let sealed = wrapped
+        .strip_prefix(WRAPPED_PREFIX)
+        .ok_or(CryptoError::Malformed)?;
+    let sealed = BASE64.decode(sealed).map_err(|_| CryptoError::Malformed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let password = wrapping_cipher(token)
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::Decryption)?;
+    String::from_utf8(password).map_err(|_| CryptoError::Malformed)
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct Share {
+    // to tell the links apart, e.g. who got it
+    pub label: String,
+    pub token_hash: String,
+    // the password of the upload, encrypted with the token
+    pub wrapped_key: Option<String>,
+    pub created: i64,
+    // 0 for links that last as long as the upload
+    pub expiration: i64,
+    // 0 for links that can be opened any number of times
+    pub max_uses: u64,
+    pub uses: u64,
+    pub last_used: i64,
+}
+
+impl Share {
+    /// A new link and its token. `password` is that of an upload encrypted on the
+    /// server, which the link needs to decrypt it.
+    pub fn new(
+        label: &str,
+        password: Option<&str>,
+        now: i64,
+        valid_hours: i64,
+        max_uses: u64,
+    ) -> (Share, String) {
+        let (token, token_hash) = manage::new_token();
+        let share = Share {
+            label: label.trim().chars().take(64).collect(),
+            token_hash,
+            wrapped_key: password.map(|password| wrap_key(password, &token)),
+            created: now,
+            expiration: if valid_hours > 0 {
+                now + valid_hours * 60 * 60
+            } else {
+                0
+            },
+            max_uses,
+            uses: 0,
+            last_used: 0,
+        };
+        (share, token)
+    }
+
+    /// A short public name of the link, to revoke it by.
+    pub fn id(&self) -> &str {
+        &self.token_hash[..12]
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expiration != 0 && self.expiration < now
+    }
+
+    /// Whether the link can be opened once more.
+    pub fn can_open(&self, now: i64) -> bool {
+        !self.is_expired(now) && (self.max_uses == 0 || self.uses < self.max_uses)
+    }
+
+    /// Whether the files of the upload can be downloaded with the link.
+    pub fn can_download(&self, now: i64) -> bool {
+        !self.is_expired(now)
+            && (self.max_uses == 0
+                || (self.uses > 0 && now < self.last_used + FILE_GRACE_SECONDS))
+    }
+
+    /// The password of the upload, if it is encrypted on the server.
+    pub fn unwrap_key(&self, token: &str) -> Result<Option<String>, CryptoError> {
+        self.wrapped_key
+            .as_ref()
+            .map(|wrapped| unwrap_key(wrapped, token))
+            .transpose()
+    }
+
+    pub fn expiration_as_string(&self) -> String {
+        if self.expiration == 0 {
+            return String::from("With the upload");
+        }
+        Local
+            .timestamp_opt(self.expiration, 0)
+            .earliest()
+            .map(|date| {
+                format!(
+                    "{:02}-{:02} {:02}:{:02}",
+                    date.month(),
+                    date.day(),
+                    date.hour(),
+                    date.minute(),
+                )
+            })
+            .unwrap_or_else(|| String::from("Unknown"))
+    }
+
+    pub fn uses_as_string(&self) -> String {
+        if self.max_uses == 0 {
+            self.uses.to_string()
+        } else {
+            format!("{} of {}", self.uses, self.max_uses)
+        }
+    }
+}
+
+/// Whether uploads of this kind can be shared. Uploads encrypted in the browser can
+/// not, the server never knows their key.
+pub fn can_share(pasta: &Pasta) -> bool {
+    !pasta.encrypt_client && !pasta.encrypt_fragment
+}
+
+/// The upload and the link a token belongs to, as indexes.
+pub fn find(pastas: &[Pasta], token: &str) -> Option<(usize, usize)> {
+    if token.is_empty() {
+        return None;
+    }
+    let hash = manage::hash(token);
+    pastas.iter().enumerate().find_map(|(i, pasta)| {
+        pasta
+            .shares
+            .iter()
+            .position(|share| bool::from(share.token_hash.as_bytes().ct_eq(hash.as_bytes())))
+            .map(|j| (i, j))
+    })
+}
+
+/// Drops the links that can no longer be opened nor used to download.
+pub fn remove_expired(pasta: &mut Pasta, now: i64) -> bool {
+    let before = pasta.shares.len();
+    pasta
+        .shares
+        .retain(|share| share.can_open(now) || share.can_download(now));
+    pasta.shares.len() != before
+}
+
+pub fn url(token: &str) -> String {
+    format!("{}/s/{}", ARGS.public_path_as_str(), token)
+}
+
+#[test]
+fn test_share_limits() {
+    let (mut share, token) = Share::new("vendor", Some("hunter2"), 1000, 1, 1);
+    assert!(share.can_open(1000));
+    assert!(!share.can_download(1000));
+    assert_eq!(share.unwrap_key(&token).unwrap().as_deref(), Some("hunter2"));
+    assert!(share.unwrap_key("another token").is_err());
+    assert!(share.wrapped_key.as_deref().unwrap().starts_with(WRAPPED_PREFIX));
+    let unprefixed = Share {
+        wrapped_key: Some(String::from("c2VhbGVk")),
+        ..share.clone()
+    };
+    assert!(matches!(unprefixed.unwrap_key(&token), Err(CryptoError::Malformed)));
+
+    share.uses = 1;
+    share.last_used = 1100;
+    assert!(!share.can_open(1200));
+    assert!(share.can_download(1200));
+    assert!(!share.can_download(1100 + FILE_GRACE_SECONDS));
+
+    // past its expiration nothing works, however few times it was used
+    let (share, _) = Share::new("", None, 1000, 1, 0);
+    assert!(share.can_open(1000 + 3600));
+    assert!(!share.can_open(1000 + 3601));
+    assert!(!share.can_download(1000 + 3601));
+}
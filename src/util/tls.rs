@@ -0,0 +1,190 @@
+//! TLS termination, so small deployments do not need a reverse proxy just for HTTPS.
+//! The certificate and key are read again whenever their files change, which picks up
+//! renewed certificates without a restart.
+
+use crate::args::ARGS;
+use actix_web::{HttpRequest, HttpResponse};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// how often the files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn is_enabled() -> bool {
+    ARGS.tls_cert.is_some()
+}
+
+/// Stops MicroBin if the certificate comes without its key, or the other way round.
+pub fn check_config() {
+    if ARGS.tls_cert.is_some() != ARGS.tls_key.is_some() {
+        panic!("MICROBIN_TLS_CERT and MICROBIN_TLS_KEY must be set together.");
+    }
+    if ARGS.tls_redirect_port.is_some() && !is_enabled() {
+        panic!("MICROBIN_TLS_REDIRECT_PORT is set, but MICROBIN_TLS_CERT is not.");
+    }
+    if ARGS.tls_redirect_port == Some(ARGS.port) {
+        panic!("MICROBIN_TLS_REDIRECT_PORT must differ from MICROBIN_PORT.");
+    }
+}
+
+#[cfg(feature = "__rustcrypto-tls")]
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls_rustcrypto::provider())
+}
+
+#[cfg(not(feature = "__rustcrypto-tls"))]
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load(
+    provider: &CryptoProvider,
+    cert_path: &str,
+    key_path: &str,
+) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Couldn't read the certificate {}: {}", cert_path, e))?;
+    if chain.is_empty() {
+        return Err(format!(
+            "The certificate {} contains no certificates.",
+            cert_path
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Couldn't read the key {}: {}", key_path, e))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| format!("Couldn't load the key: {}", e))?;
+
+    let certified_key = CertifiedKey::new(chain, signing_key);
+    // providers that cannot tell are trusted with the pair
+    if let Err(rustls::Error::InconsistentKeys(_)) = certified_key.keys_match() {
+        return Err(format!(
+            "The key {} does not belong to the certificate {}.",
+            key_path, cert_path
+        ));
+    }
+
+    Ok(certified_key)
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Hands out the certificate last read from the files.
+#[derive(Debug)]
+struct ReloadingResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// The configuration of the HTTPS listener, with a thread that reloads the certificate
+/// and key when either file changes. A broken renewal keeps the last good certificate.
+pub fn server_config() -> ServerConfig {
+    let (cert, key) = match (&ARGS.tls_cert, &ARGS.tls_key) {
+        (Some(cert), Some(key)) => (cert.to_owned(), key.to_owned()),
+        _ => panic!("TLS is not configured."),
+    };
+
+    let provider = provider();
+    let resolver = Arc::new(ReloadingResolver {
+        key: RwLock::new(Arc::new(
+            load(&provider, &cert, &key).unwrap_or_else(|e| panic!("{}", e)),
+        )),
+    });
+
+    let watched = resolver.clone();
+    let reload_provider = provider.clone();
+    thread::spawn(move || {
+        let mut last_seen = (modified(&cert), modified(&key));
+        loop {
+            thread::sleep(RELOAD_INTERVAL);
+
+            let seen = (modified(&cert), modified(&key));
+            if seen == last_seen {
+                continue;
+            }
+
+            // a renewal may have written only one of the files yet, so failures are
+            // tried again until both fit
+            match load(&reload_provider, &cert, &key) {
+                Ok(certified_key) => {
+                    *watched.key.write().unwrap() = Arc::new(certified_key);
+                    last_seen = seen;
+                    log::info!("Reloaded the TLS certificate from {}.", cert);
+                }
+                Err(e) => log::error!("Keeping the previous TLS certificate. {}", e),
+            }
+        }
+    });
+
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("Should support safe default protocols")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+// the host of a Host header, without its port
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+/// Sends the requests to the plain HTTP listener over to HTTPS, at the public path if
+/// it is an HTTPS one. Otherwise the host they were sent to is kept, with the port of
+/// the HTTPS listener.
+pub async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+
+    let public_path = ARGS.public_path_as_str();
+    let location = if public_path.starts_with("https://") {
+        format!("{}{}", public_path.trim_end_matches('/'), path)
+    } else {
+        let port = if ARGS.port == 443 {
+            String::new()
+        } else {
+            format!(":{}", ARGS.port)
+        };
+        format!(
+            "https://{}{}{}",
+            host_name(req.connection_info().host()),
+            port,
+            path
+        )
+    };
+
+    HttpResponse::PermanentRedirect()
+        .append_header(("Location", location))
+        .finish()
+}
+
+#[test]
+fn test_host_name() {
+    assert_eq!(host_name("pastes.example.com:80"), "pastes.example.com");
+    assert_eq!(host_name("pastes.example.com"), "pastes.example.com");
+    assert_eq!(host_name("[2001:db8::1]:8080"), "[2001:db8::1]");
+}
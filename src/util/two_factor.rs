@@ -0,0 +1,243 @@
+//! Optional TOTP second factor for the admin login. The secret and the hashed one-time
+//! recovery codes are kept in `admin_2fa.json` in the data directory; deleting that file
+//! turns two-factor authentication off again.
+
+use crate::args::ARGS;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const FILE_NAME: &str = "admin_2fa.json";
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const STEP: u64 = 30;
+
+#[derive(Serialize, Deserialize)]
+struct Enrolment {
+    secret: String,
+    recovery_codes: Vec<String>,
+    // the last time step a code was accepted for, so a code cannot be used twice
+    #[serde(default)]
+    last_step: u64,
+}
+
+pub struct TwoFactor {
+    enrolment: Mutex<Option<Enrolment>>,
+}
+
+fn file_path() -> String {
+    format!("{}/{}", ARGS.data_dir, FILE_NAME)
+}
+
+fn totp(secret: &str) -> Option<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        STEP,
+        bytes,
+        Some(String::from("MicroBin")),
+        String::from("admin"),
+    )
+    .ok()
+}
+
+fn current_step() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs() / STEP,
+        Err(_) => {
+            log::error!("SystemTime before UNIX EPOCH!");
+            0
+        }
+    }
+}
+
+/// Returns the time step the code is valid for, allowing one step of clock drift.
+fn matching_step(totp: &TOTP, code: &str) -> Option<u64> {
+    let now = current_step();
+    (now.saturating_sub(1)..=now + 1).find(|step| {
+        totp.generate(step * STEP)
+            .as_bytes()
+            .ct_eq(code.as_bytes())
+            .into()
+    })
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(normalize_recovery_code(code).as_bytes()))
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..12)
+                .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+                .collect();
+            format!("{}-{}-{}", &code[0..4], &code[4..8], &code[8..12])
+        })
+        .collect()
+}
+
+fn save(enrolment: &Option<Enrolment>) {
+    let result = match enrolment {
+        Some(enrolment) => serde_json::to_string_pretty(enrolment)
+            .map_err(std::io::Error::from)
+            .and_then(|json| {
+                let tmp_path = format!("{}.tmp", file_path());
+                fs::write(&tmp_path, json)?;
+                fs::rename(&tmp_path, file_path())
+            }),
+        None => fs::remove_file(file_path()),
+    };
+    if let Err(e) = result {
+        log::error!("Failed to update {}: {}", file_path(), e);
+    }
+}
+
+/// A new random secret, base32 encoded as authenticator apps expect it.
+pub fn new_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+/// The otpauth:// URI authenticator apps read from the QR code.
+pub fn provisioning_uri(secret: &str) -> String {
+    totp(secret).map(|totp| totp.get_url()).unwrap_or_default()
+}
+
+impl TwoFactor {
+    /// Reads the enrolment from the data directory. A file that exists but cannot be
+    /// read stops MicroBin rather than silently turning the second factor off.
+    pub fn load() -> Self {
+        let enrolment = match fs::read_to_string(file_path()) {
+            Ok(json) => Some(
+                serde_json::from_str::<Enrolment>(&json)
+                    .unwrap_or_else(|e| panic!("Failed to read {}: {}", file_path(), e)),
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => panic!("Failed to read {}: {}", file_path(), e),
+        };
+
+        TwoFactor {
+            enrolment: Mutex::new(enrolment),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enrolment.lock().unwrap().is_some()
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.enrolment
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |enrolment| enrolment.recovery_codes.len())
+    }
+
+    /// Accepts a current TOTP code or one of the recovery codes, which is used up. Any
+    /// code is accepted while two-factor authentication is off.
+    pub fn verify(&self, code: &str) -> bool {
+        let code = code.trim();
+        let mut guard = self.enrolment.lock().unwrap();
+        let enrolment = match guard.as_mut() {
+            Some(enrolment) => enrolment,
+            None => return true,
+        };
+
+        if let Some(step) = totp(&enrolment.secret).and_then(|totp| matching_step(&totp, code)) {
+            if step <= enrolment.last_step {
+                return false;
+            }
+            enrolment.last_step = step;
+            save(&guard);
+            return true;
+        }
+
+        let hash = hash_recovery_code(code);
+        if let Some(index) = enrolment
+            .recovery_codes
+            .iter()
+            .position(|stored| bool::from(stored.as_bytes().ct_eq(hash.as_bytes())))
+        {
+            enrolment.recovery_codes.remove(index);
+            log::warn!(
+                "Admin signed in with a recovery code, {} left.",
+                enrolment.recovery_codes.len()
+            );
+            save(&guard);
+            return true;
+        }
+
+        false
+    }
+
+    /// Turns the second factor on once the code shows the authenticator was set up with
+    /// the secret. Returns the recovery codes, which are only ever shown this once.
+    pub fn enable(&self, secret: &str, code: &str) -> Option<Vec<String>> {
+        let step = totp(secret).and_then(|totp| matching_step(&totp, code.trim()))?;
+
+        let recovery_codes = generate_recovery_codes();
+        let mut guard = self.enrolment.lock().unwrap();
+        *guard = Some(Enrolment {
+            secret: secret.to_string(),
+            recovery_codes: recovery_codes.iter().map(|code| hash_recovery_code(code)).collect(),
+            last_step: step,
+        });
+        save(&guard);
+
+        Some(recovery_codes)
+    }
+
+    /// Replaces all recovery codes with new ones.
+    pub fn regenerate_recovery_codes(&self) -> Option<Vec<String>> {
+        let mut guard = self.enrolment.lock().unwrap();
+        let enrolment = guard.as_mut()?;
+
+        let recovery_codes = generate_recovery_codes();
+        enrolment.recovery_codes = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+        save(&guard);
+
+        Some(recovery_codes)
+    }
+
+    pub fn disable(&self) {
+        let mut guard = self.enrolment.lock().unwrap();
+        *guard = None;
+        save(&guard);
+    }
+}
+
+#[test]
+fn test_recovery_code_hashing() {
+    let codes = generate_recovery_codes();
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert_eq!(
+        hash_recovery_code(&codes[0]),
+        hash_recovery_code(&codes[0].to_uppercase().replace('-', " "))
+    );
+    assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+}
+
+#[test]
+fn test_totp_code_matches_current_step() {
+    let secret = new_secret();
+    let totp = totp(&secret).unwrap();
+    let code = totp.generate(current_step() * STEP);
+    assert!(matching_step(&totp, &code).is_some());
+    assert!(matching_step(&totp, "not a code").is_none());
+}
@@ -0,0 +1,297 @@
+//! User accounts, created by the admin. Users sign in with their own password and own
+//! the uploads they create while signed in. Accounts are kept in `users.json` in the data
+//! directory, with passwords hashed with Argon2id. Errors are returned as the status the
+//! pages show a message for.
+//!
+//! Signing in through single sign-on or a trusted reverse proxy creates an account on
+//! first use, linked to the subject the identity provider or the proxy names the user
+//! with. These accounts have no password.
+
+use crate::args::ARGS;
+use crate::util::misc::now;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+
+const FILE_NAME: &str = "users.json";
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+
+lazy_static! {
+    // checked against when the username does not exist, so a failed login takes as
+    // long either way and does not reveal which usernames exist
+    static ref DUMMY_HASH: String = hash_password("microbin dummy password");
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    #[serde(default)]
+    password_hash: String,
+    pub created: i64,
+    #[serde(default)]
+    pub sso_subject: Option<String>,
+}
+
+impl User {
+    pub fn created_as_string(&self) -> String {
+        Local
+            .timestamp_opt(self.created, 0)
+            .earliest()
+            .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn is_sso(&self) -> bool {
+        self.sso_subject.is_some()
+    }
+}
+
+pub struct UserStore {
+    users: Mutex<Vec<User>>,
+}
+
+fn file_path() -> String {
+    format!("{}/{}", ARGS.data_dir, FILE_NAME)
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Argon2 hashing with default parameters cannot fail")
+        .to_string()
+}
+
+fn password_matches(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// Usernames are 1 to 32 letters, digits, dots, dashes and underscores.
+fn validate_username(username: &str) -> Result<(), &'static str> {
+    if username.is_empty()
+        || username.len() > MAX_USERNAME_LEN
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err("invalid_username");
+    }
+    Ok(())
+}
+
+/// Turns a name from the identity provider into a valid username.
+fn sanitize_username(name: &str) -> String {
+    let username: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_USERNAME_LEN)
+        .collect();
+    if username.is_empty() {
+        String::from("user")
+    } else {
+        username
+    }
+}
+
+fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err("short_password");
+    }
+    Ok(())
+}
+
+fn save(users: &[User]) {
+    let result = serde_json::to_string_pretty(users)
+        .map_err(std::io::Error::from)
+        .and_then(|json| {
+            let tmp_path = format!("{}.tmp", file_path());
+            fs::write(&tmp_path, json)?;
+            fs::rename(&tmp_path, file_path())
+        });
+    if let Err(e) = result {
+        log::error!("Failed to update {}: {}", file_path(), e);
+    }
+}
+
+impl UserStore {
+    /// Reads the accounts from the data directory. A file that exists but cannot be read
+    /// stops MicroBin rather than silently locking everyone out.
+    pub fn load() -> Self {
+        let users = match fs::read_to_string(file_path()) {
+            Ok(json) => serde_json::from_str::<Vec<User>>(&json)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", file_path(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => panic!("Failed to read {}: {}", file_path(), e),
+        };
+
+        UserStore {
+            users: Mutex::new(users),
+        }
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users.lock().unwrap().clone()
+    }
+
+    pub fn find(&self, id: u64) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.id == id)
+            .cloned()
+    }
+
+    pub fn username(&self, id: u64) -> Option<String> {
+        self.find(id).map(|user| user.username)
+    }
+
+    pub fn create(&self, username: &str, password: &str) -> Result<User, &'static str> {
+        let username = username.trim();
+        validate_username(username)?;
+        validate_password(password)?;
+
+        let mut users = self.users.lock().unwrap();
+        if users
+            .iter()
+            .any(|user| user.username.eq_ignore_ascii_case(username))
+        {
+            return Err("username_taken");
+        }
+
+        let user = User {
+            id: users.iter().map(|user| user.id).max().unwrap_or(0) + 1,
+            username: username.to_string(),
+            password_hash: hash_password(password),
+            created: now(),
+            sso_subject: None,
+        };
+        users.push(user.clone());
+        save(&users);
+
+        Ok(user)
+    }
+
+    /// The account linked to a single sign-on subject, created on first sign in. The
+    /// username is taken from the name the identity provider suggests, with a number
+    /// added if it is already in use.
+    pub fn find_or_create_sso(&self, subject: &str, name: &str) -> User {
+        let mut users = self.users.lock().unwrap();
+        if let Some(user) = users
+            .iter()
+            .find(|user| user.sso_subject.as_deref() == Some(subject))
+        {
+            return user.clone();
+        }
+
+        let base = sanitize_username(name);
+        let mut username = base.clone();
+        let mut n = 2;
+        while users
+            .iter()
+            .any(|user| user.username.eq_ignore_ascii_case(&username))
+        {
+            let suffix = format!("-{}", n);
+            let keep = base.len().min(MAX_USERNAME_LEN - suffix.len());
+            username = format!("{}{}", &base[..keep], suffix);
+            n += 1;
+        }
+
+        let user = User {
+            id: users.iter().map(|user| user.id).max().unwrap_or(0) + 1,
+            username,
+            password_hash: String::new(),
+            created: now(),
+            sso_subject: Some(subject.to_string()),
+        };
+        users.push(user.clone());
+        save(&users);
+
+        user
+    }
+
+    /// Returns the id of the user if the password is theirs. Usernames are not case
+    /// sensitive.
+    pub fn verify(&self, username: &str, password: &str) -> Option<u64> {
+        let user = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.username.eq_ignore_ascii_case(username.trim()))
+            .cloned();
+
+        match user {
+            Some(user) if password_matches(&user.password_hash, password) => Some(user.id),
+            Some(_) => None,
+            None => {
+                password_matches(&DUMMY_HASH, password);
+                None
+            }
+        }
+    }
+
+    pub fn set_password(&self, id: u64, password: &str) -> Result<(), &'static str> {
+        validate_password(password)?;
+
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .iter_mut()
+            .find(|user| user.id == id)
+            .ok_or("unknown_user")?;
+        if user.is_sso() {
+            return Err("sso_account");
+        }
+        user.password_hash = hash_password(password);
+        save(&users);
+
+        Ok(())
+    }
+
+    pub fn delete(&self, id: u64) -> Option<User> {
+        let mut users = self.users.lock().unwrap();
+        let index = users.iter().position(|user| user.id == id)?;
+        let user = users.remove(index);
+        save(&users);
+        Some(user)
+    }
+}
+
+#[test]
+fn test_username_and_password_rules() {
+    assert!(validate_username("jane.doe-2").is_ok());
+    assert!(validate_username("").is_err());
+    assert!(validate_username("jane doe").is_err());
+    assert!(validate_username(&"a".repeat(33)).is_err());
+    assert!(validate_password("short").is_err());
+    assert!(validate_password("long enough").is_ok());
+
+    let hash = hash_password("long enough");
+    assert!(password_matches(&hash, "long enough"));
+    assert!(!password_matches(&hash, "long enougH"));
+    assert!(!password_matches("", "long enough"));
+
+    assert_eq!(sanitize_username("jane@example.com"), "jane_example.com");
+    assert_eq!(sanitize_username(" "), "user");
+}